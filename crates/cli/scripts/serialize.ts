@@ -2,6 +2,7 @@ import {
   CacheDirectoryOptions,
   FilePath,
   Job,
+  Matrix,
   Pipeline,
   Shell,
   Step,
@@ -62,6 +63,11 @@ type SerializedTrigger =
     type: "options";
     push?: SerializedTriggerOn;
     pullRequest?: SerializedTriggerOn;
+    tag?: SerializedTriggerOn;
+    schedule?: SerializedTriggerOn;
+    manual?: SerializedTriggerOn;
+    paths?: string[];
+    pathsIgnore?: string[];
   }
   | {
     type: "denoFunction";
@@ -71,6 +77,7 @@ type SerializedPipeline = {
   type: "pipeline";
   jobs: SerializedJob[];
   on?: SerializedTrigger;
+  maxParallel?: number;
 };
 
 type SerializedRun =
@@ -105,6 +112,21 @@ type SerializedJob = {
   workingDirectory: string | undefined;
   dependsOn: string[] | undefined;
   onFail: "ignore" | "stop" | undefined;
+  artifacts: string[] | undefined;
+  matrix: Matrix | undefined;
+  platform: string | undefined;
+  timeout: number | undefined;
+  retries: number | undefined;
+  retryBackoff: number | undefined;
+};
+
+type SerializedImage = SerializedJob & {
+  type: "image";
+  entrypoint: string[] | undefined;
+  cmd: string[] | undefined;
+  exposedPorts: string[] | undefined;
+  user: string | undefined;
+  stopSignal: string | undefined;
 };
 
 type SerializedStep = {
@@ -116,6 +138,10 @@ type SerializedStep = {
   secrets: string[] | undefined;
   workingDirectory: string | undefined;
   shell: SerializedShell | undefined;
+  artifacts: string[] | undefined;
+  timeout: number | undefined;
+  retries: number | undefined;
+  retryBackoff: number | undefined;
 };
 
 const serializeShell = (shell: Shell): SerializedShell => {
@@ -166,6 +192,10 @@ const serializeStep = (step: Step): SerializedStep => {
       secrets: undefined,
       workingDirectory: undefined,
       shell: undefined,
+      artifacts: undefined,
+      timeout: undefined,
+      retries: undefined,
+      retryBackoff: undefined,
     };
   } else {
     return {
@@ -177,6 +207,10 @@ const serializeStep = (step: Step): SerializedStep => {
       ignoreCache: step.ignoreCache,
       workingDirectory: step.workingDirectory,
       shell: step.shell ? serializeShell(step.shell) : undefined,
+      artifacts: step.artifacts,
+      timeout: step.timeout,
+      retries: step.retries,
+      retryBackoff: step.retryBackoff,
     };
   }
 };
@@ -197,12 +231,37 @@ const serializeTriggerOn = (
 };
 
 const serializeTrigger = (trigger: Trigger): SerializedTrigger => {
+  // Trigger functions are evaluated by `cicada run` with the trigger script
+  if (typeof trigger === "function") {
+    return {
+      type: "denoFunction",
+    };
+  }
+
   return {
     type: "options",
     push: trigger.push ? serializeTriggerOn(trigger.push) : undefined,
     pullRequest: trigger.pullRequest
       ? serializeTriggerOn(trigger.pullRequest)
       : undefined,
+    tag: trigger.tag ? serializeTriggerOn(trigger.tag) : undefined,
+    schedule: trigger.schedule ? serializeTriggerOn(trigger.schedule) : undefined,
+    manual: trigger.manual ? serializeTriggerOn(trigger.manual) : undefined,
+    paths: trigger.paths,
+    pathsIgnore: trigger.pathsIgnore,
+  };
+};
+
+// A list of platforms is expanded by cicada the same as a matrix
+const serializeMatrix = (job: Job): Matrix | undefined => {
+  const platform = job.options.platform;
+  if (!Array.isArray(platform)) {
+    return job.options.matrix;
+  }
+
+  return {
+    ...job.options.matrix,
+    platform: [...(job.options.matrix?.platform ?? []), ...platform],
   };
 };
 
@@ -217,6 +276,41 @@ const serializeJob = (job: Job): SerializedJob => {
     workingDirectory: job.options.workingDirectory,
     dependsOn: job.options.dependsOn?.map((j) => j._uuid),
     onFail: job.options.onFail,
+    artifacts: job.options.artifacts,
+    matrix: serializeMatrix(job),
+    platform: Array.isArray(job.options.platform)
+      ? undefined
+      : job.options.platform,
+    timeout: job.options.timeout,
+    retries: job.options.retries,
+    retryBackoff: job.options.retryBackoff,
+  };
+};
+
+// A string command is run by a shell, the same as the shell form in a Dockerfile
+const serializeImageCommand = (
+  command: string[] | string | undefined,
+): string[] | undefined => {
+  if (typeof command === "string") {
+    return ["/bin/sh", "-c", command];
+  }
+
+  return command;
+};
+
+const serializeImage = (image: Image): SerializedImage => {
+  const job = new Job({
+    ...image.options,
+  });
+
+  return {
+    type: "image",
+    ...serializeJob(job),
+    entrypoint: serializeImageCommand(image.options.entrypoint),
+    cmd: serializeImageCommand(image.options.cmd),
+    exposedPorts: image.options.exposedPorts?.map((port) => port.toString()),
+    user: image.options.user,
+    stopSignal: image.options.stopSignal,
   };
 };
 
@@ -233,6 +327,7 @@ const serializePipeline = (pipeline: Pipeline): SerializedPipeline => {
     on: pipeline.options?.on
       ? serializeTrigger(pipeline.options?.on)
       : undefined,
+    maxParallel: pipeline.options?.maxParallel,
   };
 };
 
@@ -240,14 +335,7 @@ let object;
 if (pipeline.type === "pipeline") {
   object = serializePipeline(pipeline);
 } else {
-  const job = new Job({
-    ...pipeline.options,
-  });
-
-  object = {
-    type: "image",
-    ...serializeJob(job),
-  };
+  object = serializeImage(pipeline);
 }
 
 await Deno.writeTextFile(outPath, JSON.stringify(object, null, 2));
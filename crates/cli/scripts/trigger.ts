@@ -0,0 +1,43 @@
+import { Pipeline, TriggerContext } from "https://deno.land/x/cicada/mod.ts";
+
+const modulePath = Deno.args[0];
+// The context is read from the file the result is written to
+const outPath = Deno.args[1];
+const context: TriggerContext = JSON.parse(await Deno.readTextFile(outPath));
+
+const module = await import(modulePath);
+const pipeline: Pipeline = module.default;
+const trigger = pipeline.options?.on;
+
+if (typeof trigger !== "function") {
+  console.error(
+    "%cError:",
+    "color: red; font-weight: bold;",
+    "Pipeline does not have a trigger function",
+  );
+  Deno.exit(1);
+}
+
+let shouldRun: boolean;
+try {
+  shouldRun = await trigger(context);
+} catch (e) {
+  console.error(
+    "%cError:",
+    "color: red; font-weight: bold;",
+    "Trigger function threw an error:",
+    e instanceof Error ? e.message : e,
+  );
+  Deno.exit(1);
+}
+
+if (typeof shouldRun !== "boolean") {
+  console.error(
+    "%cError:",
+    "color: red; font-weight: bold;",
+    "Trigger function must return a boolean",
+  );
+  Deno.exit(1);
+}
+
+await Deno.writeTextFile(outPath, JSON.stringify(shouldRun));
@@ -1,4 +1,5 @@
 use ahash::HashMap;
+use ahash::HashSet;
 
 use ahash::HashMapExt;
 use anyhow::bail;
@@ -15,6 +16,25 @@ impl Node {
     pub fn new(id: Uuid, edges: Vec<Uuid>) -> Self {
         Self { id, edges }
     }
+
+    pub fn id(&self) -> Uuid {
+        self.id
+    }
+
+    pub fn edges(&self) -> &[Uuid] {
+        &self.edges
+    }
+}
+
+/// Replace edges to jobs that were expanded, like matrix jobs, with edges to all of their expansions
+pub fn fan_out(edges: &[Uuid], expansions: &HashMap<Uuid, Vec<Uuid>>) -> Vec<Uuid> {
+    edges
+        .iter()
+        .flat_map(|edge| match expansions.get(edge) {
+            Some(expanded) => expanded.clone(),
+            None => vec![*edge],
+        })
+        .collect()
 }
 
 pub fn invert_graph(graph: &[Node]) -> Vec<Node> {
@@ -46,7 +66,13 @@ pub fn topological_sort(graph: &[Node]) -> Result<Vec<Vec<Uuid>>, Error> {
 
     for node in graph {
         for edge in &node.edges {
-            *in_degree.get_mut(edge).unwrap() += 1;
+            match in_degree.get_mut(edge) {
+                Some(degree) => *degree += 1,
+                None => bail!(
+                    "job {} has an edge to a job that is not in the graph: {edge}",
+                    node.id
+                ),
+            }
         }
     }
 
@@ -58,10 +84,9 @@ pub fn topological_sort(graph: &[Node]) -> Result<Vec<Vec<Uuid>>, Error> {
 
     while !queue.is_empty() {
         let mut current = Vec::new();
-        let size = queue.len();
 
-        for _ in 0..size {
-            let node = queue.pop().unwrap();
+        // Nodes that become ready go into the next stage, after every node they depend on
+        for node in std::mem::take(&mut queue) {
             current.push(node);
 
             if let Some(n) = graph_map.get(&node) {
@@ -84,3 +109,282 @@ pub fn topological_sort(graph: &[Node]) -> Result<Vec<Vec<Uuid>>, Error> {
 
     Ok(execution_graph)
 }
+
+/// Reduce a graph to the selected jobs and, if `include_dependencies` is set, the jobs they
+/// transitively depend on. Excluded jobs are never included and edges to removed jobs are dropped.
+pub fn reduce(
+    graph: &[Node],
+    selected: &HashSet<Uuid>,
+    excluded: &HashSet<Uuid>,
+    include_dependencies: bool,
+) -> Vec<Node> {
+    let graph_map: HashMap<Uuid, &Node> = graph.iter().map(|node| (node.id, node)).collect();
+
+    let mut kept: HashSet<Uuid> = HashSet::default();
+    let mut queue: Vec<Uuid> = selected
+        .iter()
+        .filter(|id| !excluded.contains(id))
+        .copied()
+        .collect();
+
+    while let Some(id) = queue.pop() {
+        if !kept.insert(id) || !include_dependencies {
+            continue;
+        }
+
+        if let Some(node) = graph_map.get(&id) {
+            queue.extend(node.edges.iter().filter(|edge| !excluded.contains(edge)));
+        }
+    }
+
+    graph
+        .iter()
+        .filter(|node| kept.contains(&node.id))
+        .map(|node| {
+            Node::new(
+                node.id,
+                node.edges
+                    .iter()
+                    .filter(|edge| kept.contains(edge))
+                    .copied()
+                    .collect(),
+            )
+        })
+        .collect()
+}
+
+/// Hands out jobs as soon as all of the jobs they depend on have finished
+#[derive(Debug, Clone)]
+pub struct Scheduler {
+    /// Jobs that have not started yet and the dependencies they are waiting on, in the order of the graph
+    pending: Vec<(Uuid, HashSet<Uuid>)>,
+}
+
+impl Scheduler {
+    /// Create a scheduler from a graph where the edges of a node are its dependencies
+    pub fn new(graph: &[Node]) -> Result<Self, Error> {
+        // Make sure every job can run before starting any
+        topological_sort(&invert_graph(graph))?;
+
+        let ids: HashSet<Uuid> = graph.iter().map(|node| node.id).collect();
+        let pending = graph
+            .iter()
+            .map(|node| {
+                let dependencies = node
+                    .edges
+                    .iter()
+                    .filter(|edge| ids.contains(edge))
+                    .copied()
+                    .collect();
+                (node.id, dependencies)
+            })
+            .collect();
+
+        Ok(Self { pending })
+    }
+
+    /// Take up to `limit` jobs that are ready to start
+    pub fn ready(&mut self, limit: usize) -> Vec<Uuid> {
+        let mut ready = vec![];
+        self.pending.retain(|(id, dependencies)| {
+            if dependencies.is_empty() && ready.len() < limit {
+                ready.push(*id);
+                false
+            } else {
+                true
+            }
+        });
+
+        ready
+    }
+
+    /// Mark a job as finished so the jobs that depend on it can start
+    pub fn finish(&mut self, id: Uuid) {
+        for (_, dependencies) in &mut self.pending {
+            dependencies.remove(&id);
+        }
+    }
+
+    /// Remove the jobs that directly or transitively depend on a failed job, they will never start
+    pub fn skip_dependents(&mut self, id: Uuid) -> Vec<Uuid> {
+        let mut skipped = vec![];
+        let mut failed = vec![id];
+
+        while let Some(failed_id) = failed.pop() {
+            let (dependents, pending) = std::mem::take(&mut self.pending)
+                .into_iter()
+                .partition::<Vec<_>, _>(|(_, dependencies)| dependencies.contains(&failed_id));
+            self.pending = pending;
+
+            for (dependent, _) in dependents {
+                skipped.push(dependent);
+                failed.push(dependent);
+            }
+        }
+
+        skipped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fan_out_replaces_expanded_edges() {
+        let (a, b, c, d) = (
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+        );
+        let mut expansions = HashMap::new();
+        expansions.insert(a, vec![b, c]);
+
+        assert_eq!(fan_out(&[a, d], &expansions), vec![b, c, d]);
+    }
+
+    #[test]
+    fn topological_sort_rejects_unknown_edges() {
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+
+        assert!(topological_sort(&[Node::new(a, vec![b])]).is_err());
+    }
+
+    #[test]
+    fn topological_sort_stages_follow_dependencies() {
+        let (a, b, c, d) = (
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+        );
+        // b depends on a, c depends on b and d, d is independent
+        let graph = vec![
+            Node::new(a, vec![]),
+            Node::new(b, vec![a]),
+            Node::new(c, vec![b, d]),
+            Node::new(d, vec![]),
+        ];
+
+        let mut stages = topological_sort(&invert_graph(&graph)).unwrap();
+        stages[0].sort();
+        let mut first = vec![a, d];
+        first.sort();
+
+        assert_eq!(stages, vec![first, vec![b], vec![c]]);
+    }
+
+    #[test]
+    fn scheduler_starts_jobs_when_their_dependencies_finish() {
+        let (a, b, c, d) = (
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+        );
+        // b and d depend on a, c depends on b
+        let graph = vec![
+            Node::new(a, vec![]),
+            Node::new(b, vec![a]),
+            Node::new(c, vec![b]),
+            Node::new(d, vec![a]),
+        ];
+
+        let mut scheduler = Scheduler::new(&graph).unwrap();
+        assert_eq!(scheduler.ready(usize::MAX), vec![a]);
+        assert!(scheduler.ready(usize::MAX).is_empty());
+
+        scheduler.finish(a);
+        assert_eq!(scheduler.ready(usize::MAX), vec![b, d]);
+
+        scheduler.finish(d);
+        assert!(scheduler.ready(usize::MAX).is_empty());
+
+        scheduler.finish(b);
+        assert_eq!(scheduler.ready(usize::MAX), vec![c]);
+    }
+
+    #[test]
+    fn scheduler_limits_ready_jobs() {
+        let (a, b, c) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let graph = vec![
+            Node::new(a, vec![]),
+            Node::new(b, vec![]),
+            Node::new(c, vec![]),
+        ];
+
+        let mut scheduler = Scheduler::new(&graph).unwrap();
+        assert_eq!(scheduler.ready(2), vec![a, b]);
+        assert!(scheduler.ready(0).is_empty());
+        assert_eq!(scheduler.ready(2), vec![c]);
+    }
+
+    #[test]
+    fn scheduler_skips_dependents_of_failed_jobs() {
+        let (a, b, c, d) = (
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+        );
+        // b depends on a, c depends on b, d is independent
+        let graph = vec![
+            Node::new(a, vec![]),
+            Node::new(b, vec![a]),
+            Node::new(c, vec![b]),
+            Node::new(d, vec![]),
+        ];
+
+        let mut scheduler = Scheduler::new(&graph).unwrap();
+        assert_eq!(scheduler.ready(usize::MAX), vec![a, d]);
+
+        assert_eq!(scheduler.skip_dependents(a), vec![b, c]);
+        scheduler.finish(d);
+        assert!(scheduler.ready(usize::MAX).is_empty());
+    }
+
+    #[test]
+    fn reduce_to_selected_jobs() {
+        let (a, b, c, d) = (
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+        );
+        // c depends on b which depends on a, d is independent
+        let graph = vec![
+            Node::new(a, vec![]),
+            Node::new(b, vec![a]),
+            Node::new(c, vec![b]),
+            Node::new(d, vec![]),
+        ];
+        let ids = |nodes: Vec<Node>| nodes.into_iter().map(|node| node.id).collect::<Vec<_>>();
+        let set = |ids: &[Uuid]| ids.iter().copied().collect::<HashSet<_>>();
+
+        assert_eq!(
+            ids(reduce(&graph, &set(&[c]), &set(&[]), true)),
+            vec![a, b, c]
+        );
+        assert_eq!(ids(reduce(&graph, &set(&[c]), &set(&[]), false)), vec![c]);
+        assert_eq!(
+            ids(reduce(&graph, &set(&[c, d]), &set(&[a]), true)),
+            vec![b, c, d]
+        );
+
+        let reduced = reduce(&graph, &set(&[c]), &set(&[b]), true);
+        assert_eq!(ids(reduced), vec![c]);
+
+        let reduced = reduce(&graph, &set(&[b, c]), &set(&[]), false);
+        assert!(reduced[0].edges.is_empty());
+        assert_eq!(reduced[1].edges, vec![b]);
+    }
+
+    #[test]
+    fn scheduler_rejects_cycles() {
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+        let graph = vec![Node::new(a, vec![b]), Node::new(b, vec![a])];
+
+        assert!(Scheduler::new(&graph).is_err());
+    }
+}
@@ -337,6 +337,9 @@ impl DebugCommand {
                         id: id.clone(),
                         session: session.id.clone(),
                         definition,
+                        exporter: None,
+                        cache_imports: vec![],
+                        cache_exports: vec![],
                     })
                     .await;
 
@@ -0,0 +1,187 @@
+use std::{
+    fs::File,
+    io::Write,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use once_cell::sync::OnceCell;
+use serde::Serialize;
+use tracing::warn;
+
+use crate::job::{JobReport, JobStatus};
+
+/// The version of the event protocol, this is bumped when an event changes in a way that is not
+/// backwards compatible. New events and fields can be added without bumping it.
+pub const VERSION: u32 = 1;
+
+static SINK: OnceCell<Mutex<File>> = OnceCell::new();
+
+/// Write events as newline delimited JSON to a file, or to an open file descriptor as `fd:<n>`
+pub fn init(target: &str) -> Result<()> {
+    let file = match target.strip_prefix("fd:") {
+        Some(fd) => {
+            let fd = fd
+                .parse()
+                .with_context(|| format!("Invalid file descriptor for events: {fd}"))?;
+            from_fd(fd)?
+        }
+        None => File::create(target)
+            .with_context(|| format!("Unable to create events file: {target}"))?,
+    };
+
+    SINK.set(Mutex::new(file))
+        .map_err(|_| anyhow::anyhow!("Events are already being written"))
+}
+
+#[cfg(unix)]
+fn from_fd(fd: i32) -> Result<File> {
+    use std::os::fd::FromRawFd;
+
+    // SAFETY: the descriptor is handed to cicada to write events to, nothing else in the process uses it
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+#[cfg(not(unix))]
+fn from_fd(_fd: i32) -> Result<File> {
+    anyhow::bail!("Writing events to a file descriptor is only supported on unix")
+}
+
+/// A run progress event, every line of the stream is one of these
+#[derive(Debug, Clone, Serialize)]
+#[serde(
+    tag = "event",
+    rename_all = "snake_case",
+    rename_all_fields = "camelCase"
+)]
+pub enum Event<'a> {
+    PipelineStarted {
+        pipeline: &'a str,
+        jobs: usize,
+    },
+    /// A job that will run once the jobs it depends on have finished
+    JobQueued {
+        job: &'a str,
+        depends_on: Vec<&'a str>,
+    },
+    JobStarted {
+        job: &'a str,
+    },
+    StepStarted {
+        job: &'a str,
+        step: &'a str,
+        cached: bool,
+    },
+    /// Output of a step, this is a chunk of output as it was read and may not be a whole line
+    StepLog {
+        job: &'a str,
+        step: &'a str,
+        stream: &'a str,
+        text: &'a str,
+    },
+    StepFinished {
+        job: &'a str,
+        step: &'a str,
+        status: &'a str,
+        cached: bool,
+        duration_ms: u64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<&'a str>,
+    },
+    JobFinished {
+        job: &'a str,
+        status: &'a str,
+        attempts: u32,
+        duration_ms: u64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    PipelineFinished {
+        status: &'a str,
+        exit_code: i32,
+        duration_ms: u64,
+    },
+}
+
+impl<'a> Event<'a> {
+    pub fn job_finished(report: &'a JobReport) -> Self {
+        Event::JobFinished {
+            job: &report.long_name,
+            status: match report.status {
+                JobStatus::Success => "success",
+                JobStatus::Failed { .. } => "failed",
+                JobStatus::TimedOut { .. } => "timed_out",
+                JobStatus::Skipped { .. } => "skipped",
+            },
+            attempts: report.attempts,
+            duration_ms: report.duration.as_millis() as u64,
+            error: (!report.status.success()).then(|| report.status.to_string()),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Line<'a> {
+    version: u32,
+    /// Milliseconds since the unix epoch
+    timestamp: u64,
+    #[serde(flatten)]
+    event: Event<'a>,
+}
+
+/// Write an event if events are enabled
+pub fn emit(event: Event) {
+    let Some(sink) = SINK.get() else {
+        return;
+    };
+
+    let line = Line {
+        version: VERSION,
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or(Duration::ZERO)
+            .as_millis() as u64,
+        event,
+    };
+
+    let mut json = match serde_json::to_vec(&line) {
+        Ok(json) => json,
+        Err(err) => {
+            warn!("Unable to serialize event: {err}");
+            return;
+        }
+    };
+    json.push(b'\n');
+
+    let mut sink = sink.lock().unwrap_or_else(|err| err.into_inner());
+    if let Err(err) = sink.write_all(&json) {
+        warn!("Unable to write event: {err}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serialize_line() {
+        let line = Line {
+            version: VERSION,
+            timestamp: 1,
+            event: Event::StepFinished {
+                job: "test (node-0)",
+                step: "npm test",
+                status: "success",
+                cached: true,
+                duration_ms: 12,
+                error: None,
+            },
+        };
+
+        assert_eq!(
+            serde_json::to_string(&line).unwrap(),
+            r#"{"version":1,"timestamp":1,"event":"step_finished","job":"test (node-0)","step":"npm test","status":"success","cached":true,"durationMs":12}"#
+        );
+    }
+}
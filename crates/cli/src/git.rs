@@ -108,6 +108,80 @@ pub async fn github_repo() -> Result<Option<Github>> {
     }
 }
 
+async fn git_output(args: &[&str]) -> Result<String> {
+    let output = Command::new("git").args(args).output().await?;
+
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "Failed to run git {}: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+
+    Ok(String::from_utf8(output.stdout)?.trim().to_owned())
+}
+
+/// The commits a github actions push or pull request covers, from the event at `GITHUB_EVENT_PATH`
+fn github_event_range() -> Option<(String, String)> {
+    let event: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(std::env::var_os("GITHUB_EVENT_PATH")?).ok()?)
+            .ok()?;
+
+    event_range(&event)
+}
+
+/// The commits of a github actions event. Pull requests cover their base to their head, the
+/// `before` and `after` of a `synchronize` event are only the commits of that push.
+fn event_range(event: &serde_json::Value) -> Option<(String, String)> {
+    let (before, after) = if event["pull_request"].is_object() {
+        (
+            event["pull_request"]["base"]["sha"].as_str()?,
+            event["pull_request"]["head"]["sha"].as_str()?,
+        )
+    } else {
+        (event["before"].as_str()?, event["after"].as_str()?)
+    };
+
+    // The push of a new branch has no commit before it
+    if before.chars().all(|c| c == '0') {
+        return None;
+    }
+
+    Some((before.to_owned(), after.to_owned()))
+}
+
+/// The files changed by the event, between its before and after commits if they are known.
+/// Otherwise between the base ref and `HEAD`, the remote branch is used if there is no local
+/// branch.
+pub async fn changed_files(base_ref: &str) -> Result<Vec<String>> {
+    if let Some((before, after)) = github_event_range() {
+        let diff = git_output(&["diff", "--name-only", &format!("{before}...{after}")]).await?;
+        return Ok(diff.lines().map(ToOwned::to_owned).collect());
+    }
+
+    let head = git_output(&["rev-parse", "HEAD"]).await?;
+
+    let mut merge_base = Err(anyhow::anyhow!("No base ref"));
+    for base in [base_ref.to_owned(), format!("origin/{base_ref}")] {
+        merge_base = git_output(&["merge-base", &base, "HEAD"]).await;
+        if merge_base.is_ok() {
+            break;
+        }
+    }
+    let merge_base =
+        merge_base.map_err(|err| anyhow::anyhow!("Failed to find base ref {base_ref}: {err}"))?;
+
+    // A push can have any number of commits, which ones are not known without the event
+    if merge_base == head {
+        anyhow::bail!("HEAD is already on {base_ref}, the commits of the push are not known");
+    }
+
+    let diff = git_output(&["diff", "--name-only", &merge_base, "HEAD"]).await?;
+
+    Ok(diff.lines().map(ToOwned::to_owned).collect())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -119,4 +193,27 @@ mod tests {
         assert_eq!(gh.owner, "cicadahq");
         assert_eq!(gh.repo, "cicada");
     }
+
+    #[test]
+    fn event_range_of_push_and_pull_request() {
+        let push = serde_json::json!({ "before": "aaa", "after": "bbb" });
+        assert_eq!(event_range(&push), Some(("aaa".into(), "bbb".into())));
+
+        let new_branch = serde_json::json!({ "before": "0000000", "after": "bbb" });
+        assert_eq!(event_range(&new_branch), None);
+
+        let synchronize = serde_json::json!({
+            "action": "synchronize",
+            "before": "ccc",
+            "after": "ddd",
+            "pull_request": {
+                "base": { "sha": "aaa" },
+                "head": { "sha": "ddd" },
+            },
+        });
+        assert_eq!(
+            event_range(&synchronize),
+            Some(("aaa".into(), "ddd".into()))
+        );
+    }
 }
@@ -0,0 +1,109 @@
+use std::fmt::Write;
+
+use ahash::HashMap;
+use clap::ValueEnum;
+
+use crate::{
+    dag::{invert_graph, Node},
+    job::Job,
+};
+
+#[derive(Debug, Clone, Copy, ValueEnum, PartialEq, Eq, Default)]
+pub enum GraphFormat {
+    #[default]
+    Dot,
+    Mermaid,
+}
+
+/// Render the expanded jobs as nodes with an edge from each job to the jobs that depend on it
+pub fn render(jobs: &[(usize, Job)], format: GraphFormat) -> String {
+    let nodes: Vec<Node> = jobs
+        .iter()
+        .map(|(_, job)| Node::new(job.uuid, job.depends_on.clone()))
+        .collect();
+
+    // Ids are the position of the job so the output is stable between runs
+    let positions: HashMap<_, _> = jobs
+        .iter()
+        .enumerate()
+        .map(|(position, (_, job))| (job.uuid, position))
+        .collect();
+    let dependents: HashMap<_, _> = invert_graph(&nodes)
+        .into_iter()
+        .map(|node| {
+            let mut edges: Vec<usize> = node.edges().iter().map(|id| positions[id]).collect();
+            edges.sort_unstable();
+            (node.id(), edges)
+        })
+        .collect();
+
+    let mut out = String::new();
+
+    match format {
+        GraphFormat::Dot => out.push_str("digraph pipeline {\n"),
+        GraphFormat::Mermaid => out.push_str("flowchart LR\n"),
+    }
+
+    for (position, (index, job)) in jobs.iter().enumerate() {
+        let label = job.name.clone().unwrap_or_else(|| job.long_name(*index));
+        match format {
+            GraphFormat::Dot => {
+                let label = label.replace('\\', "\\\\").replace('"', "\\\"");
+                writeln!(out, "    job{position} [label=\"{label}\"];").unwrap();
+            }
+            GraphFormat::Mermaid => {
+                let label = label.replace('"', "#quot;");
+                writeln!(out, "    job{position}[\"{label}\"]").unwrap();
+            }
+        }
+    }
+
+    for (position, (_, job)) in jobs.iter().enumerate() {
+        for dependent in &dependents[&job.uuid] {
+            match format {
+                GraphFormat::Dot => writeln!(out, "    job{position} -> job{dependent};").unwrap(),
+                GraphFormat::Mermaid => {
+                    writeln!(out, "    job{position} --> job{dependent}").unwrap()
+                }
+            }
+        }
+    }
+
+    if format == GraphFormat::Dot {
+        out.push_str("}\n");
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::job::{test_jobs, test_uuid};
+
+    fn jobs() -> Vec<(usize, Job)> {
+        test_jobs(serde_json::json!([
+            { "name": "say \"hi\"" },
+            { "dependsOn": [test_uuid(0)] },
+        ]))
+        .into_iter()
+        .enumerate()
+        .collect()
+    }
+
+    #[test]
+    fn dot() {
+        assert_eq!(
+            render(&jobs(), GraphFormat::Dot),
+            "digraph pipeline {\n    job0 [label=\"say \\\"hi\\\"\"];\n    job1 [label=\"node-1\"];\n    job0 -> job1;\n}\n"
+        );
+    }
+
+    #[test]
+    fn mermaid() {
+        assert_eq!(
+            render(&jobs(), GraphFormat::Mermaid),
+            "flowchart LR\n    job0[\"say #quot;hi#quot;\"]\n    job1[\"node-1\"]\n    job0 --> job1\n"
+        );
+    }
+}
@@ -1,30 +1,47 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap, HashSet},
+    ffi::OsStr,
+    fmt,
+    num::NonZeroUsize,
     path::{Path, PathBuf},
-    process::{ExitStatus, Stdio},
     sync::Arc,
+    time::{Duration, Instant},
 };
 
 use anyhow::Context;
-use buildkit_rs::{llb::Platform, reference::Reference, util::oci::OciBackend};
+use buildkit_rs::{
+    client::{
+        random_id, session::secret::SecretSource, Client, ExportOutput, Exporter, SessionOptions,
+        SolveOptions,
+    },
+    llb::Platform,
+    proto::moby::buildkit::v1::{CacheOptionsEntry, StatusResponse},
+    reference::Reference,
+    util::oci::OciBackend,
+};
 use camino::Utf8PathBuf;
+use futures::StreamExt;
+use oci_spec::image::{ConfigBuilder, ImageConfiguration, ImageConfigurationBuilder};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
-    process::Command,
+use tokio::process::Command;
+use tracing::{error, info, warn, Instrument};
+
+use crate::{
+    bin_deps::DENO_VERSION,
+    events::{self, Event},
+    git::Github,
+    pattern,
 };
-use tracing::{error, info, Instrument};
 
-use crate::{bin_deps::DENO_VERSION, git::Github};
-
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 pub enum OnFail {
     Ignore,
     Stop,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 pub enum CacheSharing {
     Shared,
@@ -42,9 +59,10 @@ impl From<CacheSharing> for buildkit_rs::llb::CacheSharingMode {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct CacheDirectory {
+    #[schemars(with = "String")]
     pub path: Utf8PathBuf,
     pub sharing: Option<CacheSharing>,
 }
@@ -65,26 +83,67 @@ impl CacheDirectory {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 #[serde(tag = "type")]
 pub enum TriggerOn {
-    Branches { branches: Vec<String> },
+    /// Branch patterns, see [`crate::pattern::Pattern`]
+    Branches {
+        branches: Vec<String>,
+    },
     All,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+impl TriggerOn {
+    pub fn matches(&self, branch: &str) -> anyhow::Result<bool> {
+        match self {
+            TriggerOn::Branches { branches } => {
+                Ok(pattern::matches(&pattern::parse(branches)?, branch))
+            }
+            TriggerOn::All => Ok(true),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 #[serde(tag = "type")]
 pub enum Trigger {
+    #[serde(rename_all = "camelCase")]
     Options {
         push: Option<TriggerOn>,
         pull_request: Option<TriggerOn>,
+        /// Tag patterns, the base ref is the tag name for tag events
+        tag: Option<TriggerOn>,
+        schedule: Option<TriggerOn>,
+        /// Manually triggered runs, like `workflow_dispatch` on github actions
+        manual: Option<TriggerOn>,
+        /// Only run if a changed file matches one of these patterns
+        #[serde(default)]
+        paths: Vec<String>,
+        /// Do not run if all changed files match these patterns
+        #[serde(default)]
+        paths_ignore: Vec<String>,
     },
     DenoFunction,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+/// Check if any of the changed files are relevant to the `paths` and `paths_ignore` patterns
+pub fn paths_match(
+    changed_files: &[String],
+    paths: &[String],
+    paths_ignore: &[String],
+) -> anyhow::Result<bool> {
+    let paths = pattern::parse(paths)?;
+    let paths_ignore = pattern::parse(paths_ignore)?;
+
+    Ok(changed_files.iter().any(|file| {
+        (paths.is_empty() || pattern::matches(&paths, file))
+            && (paths_ignore.is_empty() || !pattern::matches(&paths_ignore, file))
+    }))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 #[serde(tag = "type")]
 pub enum Shell {
@@ -93,7 +152,7 @@ pub enum Shell {
     Args { args: Vec<String> },
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 #[serde(tag = "type")]
 pub enum StepRun {
@@ -102,7 +161,7 @@ pub enum StepRun {
     DenoFunction,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct Step {
     pub run: StepRun,
@@ -114,11 +173,49 @@ pub struct Step {
     env: HashMap<String, String>,
     #[serde(default)]
     pub secrets: Vec<String>,
+    #[schemars(with = "Option<String>")]
     pub working_directory: Option<Utf8PathBuf>,
     pub shell: Option<Shell>,
+    #[serde(default)]
+    #[schemars(with = "Vec<String>")]
+    pub artifacts: Vec<Utf8PathBuf>,
+    /// Seconds the step may run for before the job is cancelled
+    pub timeout: Option<u64>,
+    /// Times to retry the job when this step fails, overrides the retries of the job
+    pub retries: Option<u32>,
+    /// Seconds to wait before the first retry, doubled for every retry after
+    pub retry_backoff: Option<u64>,
 }
 
 impl Step {
+    /// The working directory of the step, relative paths are joined onto the job working directory
+    fn working_directory(&self, parent_working_directory: &Utf8PathBuf) -> Utf8PathBuf {
+        match &self.working_directory {
+            Some(working_directory) if working_directory.is_absolute() => working_directory.clone(),
+            Some(working_directory) => parent_working_directory.join(working_directory),
+            None => parent_working_directory.clone(),
+        }
+    }
+
+    /// The name of the step in the build, this is also the name of its vertex. The index keeps
+    /// the names of steps running the same command apart.
+    pub fn display_name(&self, step_index: usize) -> String {
+        match (&self.name, &self.run) {
+            (Some(name), StepRun::Command { command }) => {
+                format!("{name} ({step_index}): {command}")
+            }
+            (Some(name), StepRun::Args { args }) => {
+                format!("{name} ({step_index}): {args}", args = args.join(" "))
+            }
+            (Some(name), StepRun::DenoFunction) => format!("{name} ({step_index})"),
+            (None, StepRun::Command { command }) => format!("Step {step_index}: {command}"),
+            (None, StepRun::Args { args }) => {
+                format!("Step {step_index}: {args}", args = args.join(" "))
+            }
+            (None, StepRun::DenoFunction) => format!("Step {step_index}"),
+        }
+    }
+
     fn to_exec<'a, 'b: 'a>(
         &'b self,
         root_mount: buildkit_rs::llb::Mount<'a>,
@@ -151,34 +248,10 @@ impl Step {
         .with_mount(root_mount);
 
         // Custom name for the step
-        exec = match (&self.name, &self.run) {
-            (Some(name), StepRun::Command { command }) => {
-                exec.with_custom_name(format!("{name} ({step_index}): {command}"))
-            }
-            (Some(name), StepRun::Args { args }) => exec.with_custom_name(format!(
-                "{name} ({step_index}): {args}",
-                args = args.join(" ")
-            )),
-            (Some(name), StepRun::DenoFunction) => {
-                exec.with_custom_name(format!("{name} ({step_index})"))
-            }
-            (None, StepRun::Command { command }) => exec.with_custom_name(command.clone()),
-            (None, StepRun::Args { args }) => exec.with_custom_name(args.join(" ")),
-            (None, StepRun::DenoFunction) => exec.with_custom_name(format!("Step {step_index}")),
-        };
+        exec = exec.with_custom_name(self.display_name(step_index));
 
         // If the step has a working directory, we need to set it
-        let working_directory = if let Some(working_directory) = &self.working_directory {
-            // This is relative to the parent working directory if it is not absolute
-
-            if working_directory.is_absolute() {
-                working_directory.clone()
-            } else {
-                parent_working_directory.join(working_directory)
-            }
-        } else {
-            parent_working_directory.clone()
-        };
+        let working_directory = self.working_directory(parent_working_directory);
 
         exec = exec.with_cwd(working_directory.clone().into());
 
@@ -222,7 +295,7 @@ impl Step {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct Job {
     pub uuid: uuid::Uuid,
@@ -233,10 +306,181 @@ pub struct Job {
     pub env: HashMap<String, String>,
     #[serde(default)]
     pub cache_directories: Vec<CacheDirectory>,
+    #[schemars(with = "Option<String>")]
     pub working_directory: Option<Utf8PathBuf>,
     #[serde(default)]
     pub depends_on: Vec<uuid::Uuid>,
     pub on_fail: Option<OnFail>,
+    #[serde(default)]
+    #[schemars(with = "Vec<String>")]
+    pub artifacts: Vec<Utf8PathBuf>,
+    pub matrix: Option<Matrix>,
+    /// The platform to run on, the platform passed to `cicada run` is used if this is not set
+    pub platform: Option<String>,
+    /// Seconds the job may run for before it is cancelled
+    pub timeout: Option<u64>,
+    /// Times to retry the job when it fails
+    pub retries: Option<u32>,
+    /// Seconds to wait before the first retry, doubled for every retry after
+    pub retry_backoff: Option<u64>,
+}
+
+impl Job {
+    pub fn display_name(&self, index: usize) -> String {
+        self.name
+            .clone()
+            .unwrap_or_else(|| format!("{}-{index}", self.image))
+    }
+
+    pub fn long_name(&self, index: usize) -> String {
+        let image = &self.image;
+        match &self.name {
+            Some(name) => format!("{name} ({image}-{index})"),
+            None => format!("{image}-{index}"),
+        }
+    }
+
+    /// How long to wait before retrying after a failed attempt, `None` if it should not be retried.
+    /// Failed steps with their own retries use those instead of the retries of the job.
+    pub fn retry_delay(&self, status: &JobStatus, attempt: u32) -> Option<Duration> {
+        let vertex = match status {
+            JobStatus::Failed { vertex, .. } => vertex.as_ref(),
+            // Retrying after the job timed out would time out again straight away
+            JobStatus::TimedOut {
+                step: Some(step), ..
+            } => Some(step),
+            JobStatus::TimedOut { step: None, .. }
+            | JobStatus::Success
+            | JobStatus::Skipped { .. } => return None,
+        };
+
+        let (retries, backoff) = vertex
+            .and_then(|vertex| {
+                self.steps
+                    .iter()
+                    .enumerate()
+                    .find(|(step_index, step)| {
+                        step.retries.is_some() && step.display_name(*step_index) == *vertex
+                    })
+                    .map(|(_, step)| (step.retries, step.retry_backoff))
+            })
+            .unwrap_or((self.retries, self.retry_backoff));
+
+        if attempt > retries.unwrap_or(0) {
+            return None;
+        }
+
+        let backoff = Duration::from_secs(backoff.unwrap_or(0));
+        Some(backoff.saturating_mul(2u32.saturating_pow(attempt - 1)))
+    }
+
+    pub fn has_artifacts(&self) -> bool {
+        !self.artifacts.is_empty() || self.steps.iter().any(|step| !step.artifacts.is_empty())
+    }
+
+    /// The declared artifacts of the job and its steps
+    pub fn artifact_paths(&self) -> impl Iterator<Item = &Utf8PathBuf> {
+        self.artifacts
+            .iter()
+            .chain(self.steps.iter().flat_map(|step| &step.artifacts))
+    }
+
+    /// Expand the matrix into one job per combination, each with a new uuid and a name
+    /// describing the combination. Jobs without a matrix are returned as is.
+    pub fn expand_matrix(self) -> Vec<Job> {
+        let Some(matrix) = self.matrix.clone() else {
+            return vec![self];
+        };
+
+        let axes: Vec<Vec<MatrixValue>> = [
+            matrix.image.into_iter().map(MatrixValue::Image).collect(),
+            matrix
+                .platform
+                .into_iter()
+                .map(MatrixValue::Platform)
+                .collect(),
+        ]
+        .into_iter()
+        .chain(matrix.env.into_iter().map(|(key, values)| {
+            values
+                .into_iter()
+                .map(|value| MatrixValue::Env(key.clone(), value))
+                .collect()
+        }))
+        .filter(|axis: &Vec<MatrixValue>| !axis.is_empty())
+        .collect();
+
+        let combinations = axes.into_iter().fold(vec![vec![]], |combinations, axis| {
+            combinations
+                .iter()
+                .flat_map(|combination: &Vec<MatrixValue>| {
+                    axis.iter().map(|value| {
+                        let mut combination = combination.clone();
+                        combination.push(value.clone());
+                        combination
+                    })
+                })
+                .collect()
+        });
+
+        combinations
+            .into_iter()
+            .map(|combination| {
+                let mut job = Job {
+                    uuid: uuid::Uuid::new_v4(),
+                    matrix: None,
+                    ..self.clone()
+                };
+
+                let mut labels = vec![];
+                for value in combination {
+                    match value {
+                        MatrixValue::Image(image) => {
+                            labels.push(image.clone());
+                            job.image = image;
+                        }
+                        MatrixValue::Platform(platform) => {
+                            labels.push(platform.clone());
+                            job.platform = Some(platform);
+                        }
+                        MatrixValue::Env(key, value) => {
+                            labels.push(format!("{key}={value}"));
+                            job.env.insert(key, value);
+                        }
+                    }
+                }
+
+                if !labels.is_empty() {
+                    job.name = Some(format!(
+                        "{} ({})",
+                        self.name.as_deref().unwrap_or(&self.image),
+                        labels.join(", ")
+                    ));
+                }
+
+                job
+            })
+            .collect()
+    }
+}
+
+/// Run a job for every combination of images, platforms and env values
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Matrix {
+    #[serde(default)]
+    pub image: Vec<String>,
+    #[serde(default)]
+    pub platform: Vec<String>,
+    #[serde(default)]
+    pub env: BTreeMap<String, Vec<String>>,
+}
+
+#[derive(Debug, Clone)]
+enum MatrixValue {
+    Image(String),
+    Platform(String),
+    Env(String, String),
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
@@ -244,6 +488,8 @@ pub struct Job {
 pub struct InspectInfo {
     #[serde(default)]
     config: InspectConfig,
+    pub architecture: Option<String>,
+    pub os: Option<String>,
 }
 
 #[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
@@ -256,14 +502,565 @@ pub struct InspectConfig {
     pub cmd: Option<Vec<String>>,
 }
 
+/// The outcome of a job
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JobStatus {
+    Success,
+    /// The solve failed, the vertex is the name of the step or operation that failed if known
+    Failed {
+        error: String,
+        vertex: Option<String>,
+        /// The exit code of the failed process if a process failed
+        exit_code: Option<i32>,
+    },
+    /// The job or one of its steps ran for longer than its timeout
+    TimedOut {
+        step: Option<String>,
+        timeout: Duration,
+    },
+    /// The job did not run, like when a job it depends on failed
+    Skipped {
+        reason: String,
+    },
+}
+
+impl JobStatus {
+    pub fn success(&self) -> bool {
+        matches!(self, JobStatus::Success)
+    }
+}
+
+impl fmt::Display for JobStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JobStatus::Success => f.write_str("success"),
+            JobStatus::Failed { error, .. } => write!(f, "failed: {error}"),
+            JobStatus::TimedOut {
+                step: Some(step),
+                timeout,
+            } => write!(f, "timed out after {}s in {step}", timeout.as_secs()),
+            JobStatus::TimedOut {
+                step: None,
+                timeout,
+            } => write!(f, "timed out after {}s", timeout.as_secs()),
+            JobStatus::Skipped { reason } => write!(f, "skipped: {reason}"),
+        }
+    }
+}
+
+/// The result of running a job
+#[derive(Debug, Clone)]
+pub struct JobReport {
+    pub long_name: String,
+    pub status: JobStatus,
+    /// How many times the job was solved, this is more than one if it was retried
+    pub attempts: u32,
+    pub duration: Duration,
+    /// The steps of the last attempt
+    pub steps: Vec<StepReport>,
+    /// The job failed but its `onFail` is ignore
+    pub ignored: bool,
+}
+
+impl JobReport {
+    /// The report of a job that failed without a status from buildkit, like when it was interrupted
+    pub fn failed(long_name: String, error: String) -> Self {
+        Self {
+            long_name,
+            status: JobStatus::Failed {
+                error,
+                vertex: None,
+                exit_code: None,
+            },
+            attempts: 0,
+            duration: Duration::ZERO,
+            steps: vec![],
+            ignored: false,
+        }
+    }
+
+    /// The report of a job that did not run
+    pub fn skipped(long_name: String, job: &Job, reason: String) -> Self {
+        Self {
+            long_name,
+            status: JobStatus::Skipped { reason },
+            attempts: 0,
+            duration: Duration::ZERO,
+            steps: job
+                .steps
+                .iter()
+                .enumerate()
+                .map(|(step_index, step)| StepReport::skipped(step.display_name(step_index)))
+                .collect(),
+            ignored: false,
+        }
+    }
+}
+
+/// The outcome of a step
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StepStatus {
+    Success,
+    Failed {
+        error: String,
+    },
+    /// The step did not run, like when an earlier step failed
+    Skipped,
+}
+
+/// The result of running a step
+#[derive(Debug, Clone)]
+pub struct StepReport {
+    pub name: String,
+    pub status: StepStatus,
+    /// If the step was served from the buildkit cache
+    pub cached: bool,
+    pub duration: Duration,
+    /// The output of the step, only kept if it failed
+    pub output: String,
+}
+
+impl StepReport {
+    fn skipped(name: String) -> Self {
+        Self {
+            name,
+            status: StepStatus::Skipped,
+            cached: false,
+            duration: Duration::ZERO,
+            output: String::new(),
+        }
+    }
+}
+
+/// How long a solve may run for, steps are matched to their vertex by name
+#[derive(Debug, Default)]
+struct Timeouts {
+    /// When the job started and how long it may run for
+    job: Option<(Instant, Duration)>,
+    steps: HashMap<String, Duration>,
+}
+
+impl Timeouts {
+    fn new(job: &Job) -> Self {
+        Self {
+            job: job
+                .timeout
+                .map(|timeout| (Instant::now(), Duration::from_secs(timeout))),
+            steps: job
+                .steps
+                .iter()
+                .enumerate()
+                .filter_map(|(step_index, step)| {
+                    step.timeout.map(|timeout| {
+                        (step.display_name(step_index), Duration::from_secs(timeout))
+                    })
+                })
+                .collect(),
+        }
+    }
+
+    /// The deadline that will be hit first and the status if it is, a timeout too long to be an
+    /// `Instant` has no deadline
+    fn next_deadline(
+        &self,
+        running_steps: &HashMap<String, (String, Instant, Duration)>,
+    ) -> Option<(Instant, JobStatus)> {
+        self.job
+            .and_then(|(started, timeout)| {
+                Some((
+                    started.checked_add(timeout)?,
+                    JobStatus::TimedOut {
+                        step: None,
+                        timeout,
+                    },
+                ))
+            })
+            .into_iter()
+            .chain(
+                running_steps
+                    .values()
+                    .filter_map(|(name, started, timeout)| {
+                        Some((
+                            started.checked_add(*timeout)?,
+                            JobStatus::TimedOut {
+                                step: Some(name.clone()),
+                                timeout: *timeout,
+                            },
+                        ))
+                    }),
+            )
+            .min_by_key(|(deadline, _)| *deadline)
+    }
+}
+
+/// Build the `type=gha` cache entry, the url and token come from the actions runtime the same way buildctl finds them
+fn gh_action_cache_entry() -> CacheOptionsEntry {
+    let mut attrs = HashMap::new();
+
+    if let Ok(url) = std::env::var("ACTIONS_CACHE_URL") {
+        attrs.insert("url".into(), url);
+    }
+    if let Ok(token) = std::env::var("ACTIONS_RUNTIME_TOKEN") {
+        attrs.insert("token".into(), token);
+    }
+
+    CacheOptionsEntry {
+        r#type: "gha".into(),
+        attrs,
+    }
+}
+
+/// Solve with buildkit, logging the build status as it comes in. The solve is cancelled if the job
+/// or a running step is past its timeout.
+async fn solve_with_status(
+    client: &mut Client,
+    options: SolveOptions,
+    long_name: &str,
+    timeouts: &Timeouts,
+    steps: &[String],
+) -> anyhow::Result<(JobStatus, StatusLog)> {
+    let mut status_stream = client
+        .status(options.id.clone())
+        .in_current_span()
+        .await
+        .with_context(|| format!("Failed to get build status for {long_name}"))?;
+
+    let solve = client.solve(options).in_current_span();
+    tokio::pin!(solve);
+
+    let mut status_log = StatusLog::new(long_name, steps);
+    // Running steps with a timeout by vertex digest
+    let mut running_steps: HashMap<String, (String, Instant, Duration)> = HashMap::new();
+
+    let res = loop {
+        let deadline = timeouts.next_deadline(&running_steps);
+        let timed_out = async move {
+            let (deadline, status) = deadline?;
+            tokio::time::sleep_until(deadline.into()).await;
+            Some(status)
+        };
+
+        tokio::select! {
+            res = &mut solve => break res,
+            Some(event) = status_stream.next() => {
+                if let Ok(status) = &event {
+                    for vertex in &status.vertexes {
+                        if vertex.completed.is_some() || !vertex.error.is_empty() {
+                            running_steps.remove(&vertex.digest);
+                        } else if vertex.started.is_some() {
+                            if let Some(timeout) = timeouts.steps.get(&vertex.name) {
+                                running_steps.entry(vertex.digest.clone()).or_insert_with(|| {
+                                    (vertex.name.clone(), Instant::now(), *timeout)
+                                });
+                            }
+                        }
+                    }
+                }
+
+                status_log.log(event);
+            }
+            Some(status) = timed_out => {
+                // Dropping the solve cancels it in buildkit
+                return Ok((status, status_log));
+            }
+        }
+    };
+
+    // Log the rest of the status once the solve is done
+    while let Some(event) = status_stream.next().await {
+        status_log.log(event);
+    }
+
+    let status = match res {
+        Ok(_) => JobStatus::Success,
+        Err(err) => {
+            let error = err.to_string();
+            // Buildkit only reports the exit code in the error of the failed vertex and the solve
+            let exit_code = status_log
+                .failed_vertex
+                .as_ref()
+                .and_then(|vertex| status_log.vertexes.get(vertex)?.error.as_deref())
+                .into_iter()
+                .chain([error.as_str()])
+                .find_map(parse_exit_code);
+
+            JobStatus::Failed {
+                error,
+                vertex: status_log.failed_vertex.clone(),
+                exit_code,
+            }
+        }
+    };
+
+    Ok((status, status_log))
+}
+
+/// The exit code of a process from a buildkit error like `process "/bin/sh -c npm test" did not
+/// complete successfully: exit code: 127`
+fn parse_exit_code(error: &str) -> Option<i32> {
+    let (_, code) = error.rsplit_once("exit code: ")?;
+    code.split(|c: char| !c.is_ascii_digit())
+        .next()?
+        .parse()
+        .ok()
+}
+
+/// Logs the vertexes, logs and warnings from a buildkit status stream
+#[derive(Debug, Default)]
+struct StatusLog {
+    /// The long name of the job, used in events
+    job: String,
+    /// The names of the steps to send events for
+    steps: HashSet<String>,
+    /// Vertex digest to name, logs and warnings only reference the digest
+    vertex_names: HashMap<String, String>,
+    started_vertexes: HashSet<String>,
+    /// The name of the last vertex that errored
+    failed_vertex: Option<String>,
+    /// Vertexes by name, steps are matched to their vertex by name
+    vertexes: HashMap<String, VertexResult>,
+    /// Output of vertexes by digest, dropped once a vertex succeeds
+    outputs: HashMap<String, Vec<u8>>,
+}
+
+/// What happened to a vertex, the times are when the status came in
+#[derive(Debug, Default)]
+struct VertexResult {
+    digest: String,
+    cached: bool,
+    started: Option<Instant>,
+    completed: Option<Instant>,
+    error: Option<String>,
+}
+
+impl StatusLog {
+    fn new(job: &str, steps: &[String]) -> Self {
+        Self {
+            job: job.to_owned(),
+            steps: steps.iter().cloned().collect(),
+            ..Default::default()
+        }
+    }
+
+    fn log<E: fmt::Display>(&mut self, event: Result<StatusResponse, E>) {
+        let StatusResponse {
+            vertexes,
+            logs,
+            warnings,
+            ..
+        } = match event {
+            Ok(status) => status,
+            Err(err) => {
+                error!("{err}");
+                return;
+            }
+        };
+
+        for log in &logs {
+            self.outputs
+                .entry(log.vertex.clone())
+                .or_default()
+                .extend_from_slice(&log.msg);
+        }
+
+        // Steps finish after their logs are sent
+        let mut finished_steps = vec![];
+
+        for vertex in vertexes {
+            let is_step = self.steps.contains(&vertex.name);
+            let result = self.vertexes.entry(vertex.name.clone()).or_default();
+            result.digest = vertex.digest.clone();
+            if vertex.started.is_some() && result.started.is_none() {
+                result.started = Some(Instant::now());
+                result.cached = vertex.cached;
+
+                if is_step {
+                    events::emit(Event::StepStarted {
+                        job: &self.job,
+                        step: &vertex.name,
+                        cached: vertex.cached,
+                    });
+                }
+            }
+            if (vertex.completed.is_some() || !vertex.error.is_empty())
+                && result.completed.is_none()
+            {
+                result.completed = Some(Instant::now());
+                if vertex.error.is_empty() {
+                    self.outputs.remove(&vertex.digest);
+                } else {
+                    result.error = Some(vertex.error.clone());
+                }
+
+                if is_step {
+                    finished_steps.push(vertex.name.clone());
+                }
+            }
+
+            if vertex.started.is_some() && self.started_vertexes.insert(vertex.digest.clone()) {
+                if vertex.cached {
+                    info!("{} (cached)", vertex.name);
+                } else {
+                    info!("{}", vertex.name);
+                }
+            }
+
+            if !vertex.error.is_empty() {
+                error!("{}: {}", vertex.name, vertex.error);
+                self.failed_vertex = Some(vertex.name.clone());
+            }
+
+            self.vertex_names.insert(vertex.digest, vertex.name);
+        }
+
+        for log in logs {
+            let msg = String::from_utf8_lossy(&log.msg);
+
+            if let Some(step) = self
+                .vertex_names
+                .get(&log.vertex)
+                .filter(|name| self.steps.contains(*name))
+            {
+                events::emit(Event::StepLog {
+                    job: &self.job,
+                    step,
+                    stream: if log.stream == 2 { "stderr" } else { "stdout" },
+                    text: &msg,
+                });
+            }
+
+            for line in msg.lines() {
+                info!("{line}");
+            }
+        }
+
+        for step in finished_steps {
+            let result = &self.vertexes[&step];
+            events::emit(Event::StepFinished {
+                job: &self.job,
+                step: &step,
+                status: if result.error.is_some() {
+                    "failed"
+                } else {
+                    "success"
+                },
+                cached: result.cached,
+                duration_ms: result
+                    .started
+                    .zip(result.completed)
+                    .map_or(0, |(started, completed)| {
+                        (completed - started).as_millis() as u64
+                    }),
+                error: result.error.as_deref(),
+            });
+        }
+
+        for warning in warnings {
+            let short = String::from_utf8_lossy(&warning.short);
+            match self.vertex_names.get(&warning.vertex) {
+                Some(name) => warn!("{name}: {short}"),
+                None => warn!("{short}"),
+            }
+        }
+    }
+
+    /// The report of each step of a job, a step that started but did not finish failed with the job
+    fn step_reports(&self, steps: &[String], status: &JobStatus) -> Vec<StepReport> {
+        steps
+            .iter()
+            .map(|name| {
+                let name = name.clone();
+                let Some(result) = self.vertexes.get(&name) else {
+                    return StepReport::skipped(name);
+                };
+                let Some(started) = result.started else {
+                    return StepReport::skipped(name);
+                };
+
+                let status = match (&result.error, result.completed) {
+                    (Some(error), _) => StepStatus::Failed {
+                        error: error.clone(),
+                    },
+                    (None, Some(_)) => StepStatus::Success,
+                    (None, None) => StepStatus::Failed {
+                        error: status.to_string(),
+                    },
+                };
+                let output = match status {
+                    StepStatus::Failed { .. } => self
+                        .outputs
+                        .get(&result.digest)
+                        .map(|output| String::from_utf8_lossy(output).into_owned())
+                        .unwrap_or_default(),
+                    _ => String::new(),
+                };
+
+                StepReport {
+                    name,
+                    status,
+                    cached: result.cached,
+                    duration: result
+                        .completed
+                        .unwrap_or_else(Instant::now)
+                        .duration_since(started),
+                    output,
+                }
+            })
+            .collect()
+    }
+}
+
+/// Where artifacts are collected inside of buildkit before being exported
+const ARTIFACTS_DIR: &str = "/cicada/artifacts";
+
+fn absolute_path(working_directory: &Utf8PathBuf, path: &Utf8PathBuf) -> Utf8PathBuf {
+    if path.is_absolute() {
+        path.clone()
+    } else {
+        working_directory.join(path)
+    }
+}
+
+/// Single quote a string for `sh`
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+/// A shell command to copy `src` into the artifacts directory, the artifact keeps the path it was declared with
+fn artifact_copy(src: &Utf8PathBuf, artifact: &Utf8PathBuf) -> String {
+    let dest =
+        Utf8PathBuf::from(ARTIFACTS_DIR).join(artifact.strip_prefix("/").unwrap_or(artifact));
+    let dest_parent = dest.parent().unwrap_or(&dest);
+
+    format!(
+        "mkdir -p {} && cp -R {} {}",
+        shell_quote(dest_parent.as_str()),
+        shell_quote(src.as_str()),
+        shell_quote(dest.as_str())
+    )
+}
+
+/// The llb for a job, `artifacts` is only set if the job or any of its steps have artifacts
+pub struct JobDefinition {
+    pub job: buildkit_rs::llb::Definition,
+    pub artifacts: Option<buildkit_rs::llb::Definition>,
+}
+
 #[derive(Debug, Clone)]
 pub struct JobResolved {
     pub job: Box<Job>,
     pub image_info: Box<InspectInfo>,
     pub image_reference: Reference,
+    /// Set when the job is an `Image`, the result is exported to each of the `image_outputs`
+    pub image_options: Option<Box<ImageOptions>>,
+    pub image_outputs: Vec<ImageOutput>,
+    /// The artifacts directories of the jobs this job depends on, they are copied into the working directory
+    pub dependency_artifacts: Vec<PathBuf>,
+    pub platform: Platform,
 }
 
 impl JobResolved {
+    #[allow(clippy::too_many_arguments)]
     pub fn to_llb(
         &self,
         module_name: impl AsRef<str>,
@@ -271,8 +1068,8 @@ impl JobResolved {
         github: &Option<Github>,
         job_index: usize,
         cicada_image: Option<impl Into<String>>,
-        platform: Platform,
-    ) -> Vec<u8> {
+        no_cache: bool,
+    ) -> JobDefinition {
         use buildkit_rs::llb::*;
 
         let working_directory = self
@@ -310,12 +1107,15 @@ impl JobResolved {
             }
         }
 
+        // Artifacts from previous runs should never end up in a job
+        local = local.with_exclude(".cicada/artifacts");
+
         let image = Image::reference(self.image_reference.clone())
-            .with_platform(platform.clone())
+            .with_platform(self.platform.clone())
             .with_resolve_mode(ResolveMode::Local);
 
         let deno_image = Image::new(format!("docker.io/denoland/deno:bin-{DENO_VERSION}"))
-            .with_platform(platform.clone());
+            .with_platform(self.platform.clone());
 
         let deno_mount = Mount::layer_readonly(deno_image.output(), "/usr/local/bin/deno")
             .with_selector("/deno");
@@ -327,7 +1127,7 @@ impl JobResolved {
                 env!("CARGO_PKG_VERSION")
             )),
         }
-        .with_platform(platform);
+        .with_platform(self.platform.clone());
 
         let cicada_mount = Mount::layer_readonly(cicada_image.output(), "/usr/local/bin/cicada")
             .with_selector("/cicada");
@@ -338,7 +1138,8 @@ impl JobResolved {
         )
         .with_mount(Mount::layer(image.output(), "/", 0))
         .with_mount(Mount::layer_readonly(local.output(), "/local"))
-        .with_custom_name("Copy local files");
+        .with_custom_name("Copy local files")
+        .ignore_cache(no_cache);
 
         let mut env = self.image_info.config.env.clone().unwrap_or_default();
 
@@ -357,13 +1158,49 @@ impl JobResolved {
 
         env.extend(self.job.env.iter().map(|(k, v)| format!("{k}={v}")));
 
+        let dependency_locals: Vec<(Local, &PathBuf)> = self
+            .dependency_artifacts()
+            .map(|(name, path)| (Local::new(name), path))
+            .collect();
+
         let mut prev_step = Arc::new(local_cp);
+
+        // Copy the artifacts of the dependencies on top of the local files
+        for (dependency_local, path) in &dependency_locals {
+            let dependency_name = path
+                .file_name()
+                .map(|name| name.to_string_lossy())
+                .unwrap_or_default();
+
+            let dependency_cp = Exec::shell(
+                "/bin/sh",
+                format!(
+                    "cp -R /dependency/. {}",
+                    shell_quote(working_directory.as_str())
+                ),
+            )
+            .with_mount(Mount::layer(
+                MultiOwnedOutput::output(&prev_step, 0),
+                "/",
+                0,
+            ))
+            .with_mount(Mount::layer_readonly(
+                dependency_local.output(),
+                "/dependency",
+            ))
+            .with_custom_name(format!("Copy artifacts from {dependency_name}"))
+            .ignore_cache(no_cache);
+
+            prev_step = Arc::new(dependency_cp);
+        }
+
+        let mut artifact_steps = vec![];
         for (step_index, step) in self.job.steps.iter().enumerate() {
             let output = MultiOwnedOutput::output(&prev_step, 0);
             let root = Mount::layer(output, "/", 0);
 
-            let exec = Arc::new(
-                step.to_exec(
+            let mut exec = step
+                .to_exec(
                     root,
                     &self.job.cache_directories,
                     &working_directory,
@@ -372,15 +1209,67 @@ impl JobResolved {
                     step_index,
                 )
                 .with_mount(deno_mount.clone())
-                .with_mount(cicada_mount.clone()),
-            );
+                .with_mount(cicada_mount.clone());
+
+            if no_cache {
+                exec = exec.ignore_cache(true);
+            }
+
+            prev_step = Arc::new(exec);
 
-            prev_step = exec;
+            if !step.artifacts.is_empty() {
+                artifact_steps.push((step_index, step, prev_step.clone()));
+            }
         }
 
-        let bytes = Definition::new(prev_step.output(0)).into_bytes();
+        let artifacts = self.job.has_artifacts().then(|| {
+            // Each step with artifacts is mounted at `/cicada/steps/<index>` and the final
+            // state is the root, everything is copied into a scratch mount that gets exported
+            let mut copies = vec![];
+
+            for (step_index, step, _) in &artifact_steps {
+                let step_working_directory = step.working_directory(&working_directory);
+                for artifact in &step.artifacts {
+                    // The working directory can be relative, it is relative to the root then
+                    let path = absolute_path(&step_working_directory, artifact);
+                    let src = Utf8PathBuf::from(format!("/cicada/steps/{step_index}"))
+                        .join(path.strip_prefix("/").unwrap_or(&path));
+                    copies.push(artifact_copy(&src, artifact));
+                }
+            }
+
+            for artifact in &self.job.artifacts {
+                copies.push(artifact_copy(
+                    &absolute_path(&working_directory, artifact),
+                    artifact,
+                ));
+            }
+
+            let mut exec = Exec::shell("/bin/sh", copies.join(" && "))
+                .with_mount(Mount::layer_readonly(prev_step.output(0), "/"))
+                .with_mount(Mount::scratch(ARTIFACTS_DIR, 0))
+                .with_custom_name("Collect artifacts")
+                .ignore_cache(no_cache);
+
+            for (step_index, _, step_state) in &artifact_steps {
+                exec = exec.with_mount(Mount::layer_readonly(
+                    MultiOwnedOutput::output(step_state, 0),
+                    format!("/cicada/steps/{step_index}"),
+                ));
+            }
 
-        bytes
+            // Artifacts inside of cache directories are not part of the filesystem so mount them too
+            for cache_directory in &self.job.cache_directories {
+                exec = exec.with_mount(cache_directory.to_mount(&working_directory));
+            }
+
+            Definition::new(Arc::new(exec).output(0))
+        });
+
+        JobDefinition {
+            job: Definition::new(prev_step.output(0)),
+            artifacts,
+        }
     }
 
     // TODO: make this take an options struct
@@ -393,176 +1282,692 @@ impl JobResolved {
         project_directory: String,
         all_secrets: Vec<(String, String)>,
         cicada_image: Option<String>,
-        buildctl_exe: PathBuf,
         no_cache: bool,
         gh_action_cache: bool,
         oci_backend: OciBackend,
-        platform: Platform,
-    ) -> anyhow::Result<(String, ExitStatus, Self)> {
-        // let name: String = self.job.name.clone().unwrap().replace('\"', "\"\"");
-
-        // let config = oci_spec::image::ConfigBuilder::default()
-        //     .user("root".to_owned())
-        //     .env(["ABC=123".to_owned()])
-        //     .cmd(["/bin/bash".to_owned()])
-        //     .entrypoint(["/app/hello-world".to_owned()])
-        //     .build()
-        //     .unwrap();
-
-        // let image_config = oci_spec::image::ImageConfigurationBuilder::default()
-        //     .config(config)
-        //     .build()
-        //     .unwrap();
-
-        // let image_config_json = serde_json::to_string(&image_config)
-        //     .context("Unable to serialize OCI spec to JSON")?
-        //     .replace('\"', "\"\"");
-
-        let mut buildctl = Command::new(&buildctl_exe);
-        buildctl
-            .arg("build")
-            .arg("--local")
-            .arg(format!("local={project_directory}"))
-            .arg("--progress")
-            .arg("plain")
-            // .arg("--output")
-            // .arg(format!(
-            //     "type=docker,\"name={name}\",\"containerimage.config={image_config_json}\""
-            // ))
-            .env(
-                "BUILDKIT_HOST",
-                format!("{}-container://cicada-buildkitd", oci_backend.as_str()),
-            );
+        artifacts_directory: PathBuf,
+    ) -> anyhow::Result<(JobReport, Self)> {
+        let long_name = self.long_name(job_index);
+        let timeouts = Timeouts::new(&self.job);
+        let started = Instant::now();
 
-        if gh_action_cache {
-            buildctl
-                .arg("--export-cache")
-                .arg("type=gha")
-                .arg("--import-cache")
-                .arg("type=gha");
-        }
+        let mut client = Client::connect(oci_backend, "cicada-buildkitd".into())
+            .in_current_span()
+            .await
+            .context("Failed to connect to buildkitd")?;
+
+        let session = client
+            .session(SessionOptions {
+                name: "cicada".into(),
+                local: [("local".into(), project_directory.clone().into())]
+                    .into_iter()
+                    .chain(
+                        self.dependency_artifacts()
+                            .map(|(name, path)| (name, path.clone())),
+                    )
+                    .collect(),
+                secrets: all_secrets
+                    .into_iter()
+                    .map(|(key, value)| (key, SecretSource::Memory(value)))
+                    .collect(),
+            })
+            .in_current_span()
+            .await
+            .with_context(|| format!("Failed to start buildkit session for {long_name}"))?;
 
-        if no_cache {
-            buildctl.arg("--no-cache");
-        }
+        let (cache_imports, cache_exports) = if gh_action_cache {
+            (vec![gh_action_cache_entry()], vec![gh_action_cache_entry()])
+        } else {
+            (vec![], vec![])
+        };
 
-        for (key, _) in &all_secrets {
-            buildctl.arg("--secret").arg(format!("id={key}"));
-        }
+        // Buildkit takes a single exporter per solve so each image output gets its own solve,
+        // only the first one does any work as the rest are served from the cache
+        let image_config = match &self.image_options {
+            Some(image_options) => Some(
+                serde_json::to_string(
+                    &image_options.image_configuration(&self.job, &self.image_info)?,
+                )
+                .context("Unable to serialize OCI spec to JSON")?,
+            ),
+            None => None,
+        };
 
-        let mut buildctl_child = buildctl
-            .envs(all_secrets)
-            .stdin(Stdio::piped())
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .spawn()?;
+        let outputs: Vec<Option<&ImageOutput>> = match &image_config {
+            Some(_) => self.image_outputs.iter().map(Some).collect(),
+            None => vec![None],
+        };
 
-        let llb_vec = self.to_llb(
-            pipeline_name,
-            &project_directory,
-            &github,
-            job_index,
-            cicada_image,
-            platform,
-        );
+        let step_names: Vec<String> = self
+            .job
+            .steps
+            .iter()
+            .enumerate()
+            .map(|(step_index, step)| step.display_name(step_index))
+            .collect();
+
+        let mut status = JobStatus::Success;
+        let mut attempts = 0;
+        let mut steps = vec![];
+        for (output_index, output) in outputs.into_iter().enumerate() {
+            // Only the first solve runs the steps, the solves for other outputs are cached
+            let solve_steps = if output_index == 0 {
+                &step_names[..]
+            } else {
+                &[]
+            };
+
+            // The docker tarball is written here before being loaded into the oci backend
+            let tarball = tempfile::NamedTempFile::new()?;
+
+            // Failed solves are retried, the steps that succeeded are cached so only the failed step runs again
+            let mut attempt = 1;
+            loop {
+                let definition = self.to_llb(
+                    &pipeline_name,
+                    &project_directory,
+                    &github,
+                    job_index,
+                    cicada_image.as_deref(),
+                    no_cache,
+                );
+
+                let exporter = match (output, &image_config) {
+                    (Some(output), Some(image_config)) => {
+                        Some(output.exporter(&self.image_tag()?, image_config, tarball.path()))
+                    }
+                    _ => None,
+                };
+
+                let (solve_status, status_log) = solve_with_status(
+                    &mut client,
+                    SolveOptions {
+                        id: random_id(),
+                        session: session.id.clone(),
+                        definition: definition.job,
+                        exporter,
+                        cache_imports: cache_imports.clone(),
+                        cache_exports: cache_exports.clone(),
+                    },
+                    &long_name,
+                    &timeouts,
+                    solve_steps,
+                )
+                .await?;
+
+                status = solve_status;
+                if output_index == 0 {
+                    steps = status_log.step_reports(&step_names, &status);
+                }
 
-        let mut stdin = buildctl_child.stdin.take().unwrap();
-        stdin.write_all(&llb_vec).in_current_span().await?;
-        stdin.shutdown().in_current_span().await?;
-        drop(stdin);
-
-        // Print the output as it comes in
-        let stderr = buildctl_child.stderr.take().unwrap();
-
-        let stderr_handle = tokio::spawn(
-            async move {
-                let mut buf_reader = BufReader::new(stderr);
-                let mut line = String::new();
-                loop {
-                    if let Err(err) = buf_reader.read_line(&mut line).in_current_span().await {
-                        error!("{err}");
-                        return;
+                match self.job.retry_delay(&status, attempt) {
+                    Some(delay) => {
+                        warn!(
+                            "{long_name} {status}, retrying in {}s (attempt {attempt})",
+                            delay.as_secs()
+                        );
+                        tokio::time::sleep(delay).await;
+                        attempt += 1;
                     }
-                    if line.is_empty() {
-                        return;
+                    None => break,
+                }
+            }
+            attempts = attempts.max(attempt);
+
+            if !status.success() {
+                break;
+            }
+
+            match output {
+                Some(ImageOutput::Load) => {
+                    let output = Command::new(oci_backend.as_str())
+                        .arg("load")
+                        .arg("-i")
+                        .arg(tarball.path())
+                        .kill_on_drop(true)
+                        .output()
+                        .in_current_span()
+                        .await
+                        .with_context(|| format!("Failed to run {} load", oci_backend.as_str()))?;
+
+                    if !output.status.success() {
+                        anyhow::bail!(
+                            "Failed to load image for {long_name} into {}: {}",
+                            oci_backend.as_str(),
+                            String::from_utf8_lossy(&output.stderr)
+                        );
                     }
 
-                    info!("{}", line.trim_end_matches('\n'));
-                    line.clear();
+                    info!(
+                        "Loaded image {} into {}",
+                        self.image_tag()?,
+                        oci_backend.as_str()
+                    );
                 }
+                Some(ImageOutput::OciLayout { path, .. }) => {
+                    info!("Wrote OCI image layout to {}", path.display());
+                }
+                Some(ImageOutput::Push { reference, .. }) => {
+                    info!("Pushed image to {reference}");
+                }
+                None => {}
             }
-            .in_current_span(),
-        );
+        }
 
-        let long_name = self.long_name(job_index);
+        // Artifacts are only exported once the job has succeeded
+        if status.success() && self.job.has_artifacts() {
+            let definition = self.to_llb(
+                &pipeline_name,
+                &project_directory,
+                &github,
+                job_index,
+                cicada_image.as_deref(),
+                no_cache,
+            );
 
-        // Stdout is the tar stream that we want to pipe to docker load
-        // let mut stdout = buildctl_child.stdout.take().unwrap();
+            let job_artifacts_directory = self.artifacts_directory(&artifacts_directory, job_index);
+
+            (status, _) = solve_with_status(
+                &mut client,
+                SolveOptions {
+                    id: random_id(),
+                    session: session.id.clone(),
+                    definition: definition
+                        .artifacts
+                        .expect("jobs with artifacts have an artifacts definition"),
+                    exporter: Some(Exporter {
+                        r#type: "local".into(),
+                        attrs: HashMap::new(),
+                        output: Some(ExportOutput::Directory(job_artifacts_directory.clone())),
+                    }),
+                    cache_imports,
+                    cache_exports,
+                },
+                &long_name,
+                &timeouts,
+                &[],
+            )
+            .await?;
+
+            if status.success() {
+                info!(
+                    "Exported artifacts to {}",
+                    job_artifacts_directory.display()
+                );
+            }
+        }
 
-        // let mut docker_load = Command::new("docker")
-        //     .arg("load")
-        //     .stdin(Stdio::piped())
-        //     .spawn()?;
+        let ignored = !status.success() && self.job.on_fail == Some(OnFail::Ignore);
 
-        // let mut docker_load_stdin = docker_load.stdin.take().unwrap();
-        // tokio::io::copy(&mut stdout, &mut docker_load_stdin)
-        //     .in_current_span()
-        //     .await?;
-        // drop(docker_load_stdin);
+        anyhow::Ok((
+            JobReport {
+                long_name,
+                status,
+                attempts,
+                duration: started.elapsed(),
+                steps,
+                ignored,
+            },
+            self,
+        ))
+    }
 
-        stderr_handle
-            .in_current_span()
-            .await
-            .with_context(|| format!("Failed to read stderr for {long_name}"))?;
-
-        // let docker_load_status = docker_load
-        //     .wait()
-        //     .in_current_span()
-        //     .await
-        //     .context("Failed to wait for docker load to finish")?;
-
-        // if !docker_load_status.success() {
-        //     return Err(anyhow::anyhow!(
-        //         "Failed to load image for {long_name} into docker"
-        //     ));
-        // }
-
-        let status = buildctl_child
-            .wait()
-            .in_current_span()
-            .await
-            .with_context(|| format!("Failed to wait for {long_name} to finish"))?;
+    /// Where the artifacts of this job are exported to inside of the artifacts directory for the run
+    pub fn artifacts_directory(&self, run_artifacts_directory: &Path, index: usize) -> PathBuf {
+        run_artifacts_directory.join(
+            self.display_name(index)
+                .replace(['\\', '/', '.', ' ', ':'], "-"),
+        )
+    }
 
-        anyhow::Ok((long_name, status, self))
+    /// The artifacts of the dependencies of this job with the name of their local source,
+    /// dependencies that did not export anything are skipped
+    fn dependency_artifacts(&self) -> impl Iterator<Item = (String, &PathBuf)> {
+        self.dependency_artifacts
+            .iter()
+            .enumerate()
+            .filter(|(_, path)| path.is_dir())
+            .map(|(index, path)| (format!("dependency-{index}"), path))
     }
 
-    pub fn display_name(&self, index: usize) -> String {
-        self.job
+    /// The normalized tag of an `Image` job, `latest` is used if the name has no tag
+    fn image_tag(&self) -> anyhow::Result<String> {
+        let name = self
+            .job
             .name
-            .clone()
-            .unwrap_or_else(|| format!("{}-{index}", self.job.image))
+            .as_deref()
+            .context("Images must have a name")?;
+
+        let mut reference = Reference::parse_normalized_named(name)
+            .with_context(|| format!("Unable to parse image name: {name}"))?;
+
+        if reference.tag.is_none() && reference.digest.is_none() {
+            reference.tag = Some("latest".into());
+        }
+
+        Ok(reference.to_string())
+    }
+
+    pub fn display_name(&self, index: usize) -> String {
+        self.job.display_name(index)
     }
 
     pub fn long_name(&self, index: usize) -> String {
-        let image = &self.job.image;
-        match &self.job.name {
-            Some(name) => format!("{name} ({image}-{index})"),
-            None => format!("{image}-{index}"),
+        self.job.long_name(index)
+    }
+}
+
+/// The container options of an `Image`, these are written to the image config
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ImageOptions {
+    pub entrypoint: Option<Vec<String>>,
+    pub cmd: Option<Vec<String>>,
+    #[serde(default)]
+    pub exposed_ports: Vec<String>,
+    pub user: Option<String>,
+    pub stop_signal: Option<String>,
+}
+
+impl ImageOptions {
+    /// Build the OCI image config for an image built from `job` on top of the base image
+    pub fn image_configuration(
+        &self,
+        job: &Job,
+        base_image: &InspectInfo,
+    ) -> anyhow::Result<ImageConfiguration> {
+        let mut env = base_image.config.env.clone().unwrap_or_default();
+        env.extend(job.env.iter().map(|(k, v)| format!("{k}={v}")));
+
+        // Setting an entrypoint resets the command of the base image, the same as a Dockerfile
+        let cmd = match (&self.entrypoint, &self.cmd) {
+            (_, Some(cmd)) => Some(cmd.clone()),
+            (Some(_), None) => None,
+            (None, None) => base_image.config.cmd.clone(),
+        };
+
+        let working_directory = job
+            .working_directory
+            .clone()
+            .unwrap_or_else(|| Utf8PathBuf::from("/app"));
+
+        let mut config = ConfigBuilder::default()
+            .env(env)
+            .working_dir(working_directory.to_string());
+
+        if let Some(entrypoint) = &self.entrypoint {
+            config = config.entrypoint(entrypoint.clone());
+        }
+        if let Some(cmd) = cmd {
+            config = config.cmd(cmd);
+        }
+        if !self.exposed_ports.is_empty() {
+            config = config.exposed_ports(self.exposed_ports.clone());
+        }
+        if let Some(user) = self.user.as_ref().or(base_image.config.user.as_ref()) {
+            config = config.user(user.clone());
+        }
+        if let Some(stop_signal) = &self.stop_signal {
+            config = config.stop_signal(stop_signal.clone());
+        }
+
+        let image_config = ImageConfigurationBuilder::default()
+            .architecture(base_image.architecture.as_deref().unwrap_or("amd64"))
+            .os(base_image.os.as_deref().unwrap_or("linux"))
+            .config(config.build()?)
+            .build()?;
+
+        Ok(image_config)
+    }
+}
+
+/// Where the result of an `Image` job is exported to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ImageOutput {
+    /// Load the image into the OCI backend
+    Load,
+    /// Write an OCI image layout, as a tarball if `tar` is set otherwise as a directory
+    OciLayout { path: PathBuf, tar: bool },
+    /// Push the image to a registry, `insecure` allows plain http registries
+    Push { reference: String, insecure: bool },
+}
+
+impl ImageOutput {
+    /// Create an OCI layout output, paths ending in `.tar` are written as a tarball
+    pub fn oci_layout(path: PathBuf) -> Self {
+        let tar = path.extension() == Some(OsStr::new("tar"));
+        ImageOutput::OciLayout { path, tar }
+    }
+
+    fn exporter(&self, tag: &str, image_config: &str, load_tarball: &Path) -> Exporter {
+        let mut attrs: HashMap<String, String> = [
+            ("name".into(), tag.into()),
+            ("containerimage.config".into(), image_config.into()),
+        ]
+        .into_iter()
+        .collect();
+
+        match self {
+            ImageOutput::Load => Exporter {
+                r#type: "docker".into(),
+                attrs,
+                output: Some(ExportOutput::File(load_tarball.to_owned())),
+            },
+            ImageOutput::OciLayout { path, tar } => {
+                attrs.insert("tar".into(), tar.to_string());
+                Exporter {
+                    r#type: "oci".into(),
+                    attrs,
+                    output: Some(if *tar {
+                        ExportOutput::File(path.clone())
+                    } else {
+                        ExportOutput::Directory(path.clone())
+                    }),
+                }
+            }
+            ImageOutput::Push {
+                reference,
+                insecure,
+            } => {
+                attrs.insert("name".into(), reference.clone());
+                attrs.insert("push".into(), "true".into());
+                if *insecure {
+                    attrs.insert("registry.insecure".into(), "true".into());
+                }
+                Exporter {
+                    r#type: "image".into(),
+                    attrs,
+                    output: None,
+                }
+            }
         }
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Image {
+    #[serde(flatten)]
+    pub job: Job,
+    #[serde(flatten)]
+    pub options: ImageOptions,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct Pipeline {
     pub jobs: Vec<Job>,
     pub on: Option<Trigger>,
+    /// The most jobs to run at the same time
+    pub max_parallel: Option<NonZeroUsize>,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 #[serde(tag = "type")]
+#[allow(clippy::large_enum_variant)]
 pub enum CicadaType {
     Pipeline(Pipeline),
-    Image(Job),
+    Image(Image),
+}
+
+/// The uuid of the job at `position` in [`test_jobs`]
+#[cfg(test)]
+pub fn test_uuid(position: usize) -> uuid::Uuid {
+    uuid::Uuid::from_u128(position as u128 + 1)
+}
+
+/// Jobs for tests from their JSON, `uuid` defaults to [`test_uuid`], `image` to `node` and
+/// `steps` to none
+#[cfg(test)]
+pub fn test_jobs(jobs: serde_json::Value) -> Vec<Job> {
+    let serde_json::Value::Array(jobs) = jobs else {
+        panic!("Expected an array of jobs");
+    };
+
+    jobs.into_iter()
+        .enumerate()
+        .map(|(position, mut job)| {
+            let fields = job.as_object_mut().expect("Expected a job object");
+            fields
+                .entry("uuid")
+                .or_insert_with(|| serde_json::json!(test_uuid(position)));
+            fields.entry("image").or_insert_with(|| "node".into());
+            fields
+                .entry("steps")
+                .or_insert_with(|| serde_json::json!([]));
+            serde_json::from_value(job).unwrap()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn schema_covers_the_pipeline_model() {
+        let schema = serde_json::to_value(schemars::schema_for!(CicadaType)).unwrap();
+
+        for definition in ["Job", "Step", "StepRun", "Trigger", "CacheDirectory"] {
+            assert!(
+                schema["definitions"].get(definition).is_some(),
+                "missing {definition}"
+            );
+        }
+        assert_eq!(
+            schema["definitions"]["CacheDirectory"]["properties"]["path"]["type"],
+            "string"
+        );
+        assert!(schema["definitions"]["Trigger"]["oneOf"][0]["properties"]
+            .get("pathsIgnore")
+            .is_some());
+    }
+
+    #[test]
+    fn shell_quote_escapes_single_quotes() {
+        assert_eq!(shell_quote("coverage"), "'coverage'");
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+    }
+
+    #[test]
+    fn artifact_copy_keeps_declared_path() {
+        assert_eq!(
+            artifact_copy(
+                &Utf8PathBuf::from("/app/target/release/app"),
+                &Utf8PathBuf::from("target/release/app")
+            ),
+            "mkdir -p '/cicada/artifacts/target/release' && cp -R '/app/target/release/app' '/cicada/artifacts/target/release/app'"
+        );
+
+        assert_eq!(
+            artifact_copy(
+                &Utf8PathBuf::from("/cicada/steps/1/usr/bin/app"),
+                &Utf8PathBuf::from("/usr/bin/app")
+            ),
+            "mkdir -p '/cicada/artifacts/usr/bin' && cp -R '/cicada/steps/1/usr/bin/app' '/cicada/artifacts/usr/bin/app'"
+        );
+    }
+
+    #[test]
+    fn paths_match_filters() {
+        let changed = |files: &[&str]| files.iter().map(|f| f.to_string()).collect::<Vec<_>>();
+        let api = vec!["services/api/**".to_owned()];
+        let docs = vec!["docs/**".to_owned(), "**.md".to_owned()];
+
+        assert!(paths_match(&changed(&["services/api/main.rs"]), &api, &[]).unwrap());
+        assert!(!paths_match(&changed(&["docs/index.md"]), &api, &[]).unwrap());
+        assert!(!paths_match(&changed(&["docs/index.md", "README.md"]), &[], &docs).unwrap());
+        assert!(paths_match(&changed(&["docs/index.md", "src/main.rs"]), &[], &docs).unwrap());
+        assert!(!paths_match(&changed(&["services/api/README.md"]), &api, &docs).unwrap());
+        assert!(!paths_match(&changed(&[]), &[], &docs).unwrap());
+    }
+
+    #[test]
+    fn step_display_names_are_unique() {
+        let job = test_jobs(serde_json::json!([{
+            "steps": [
+                { "run": { "type": "command", "command": "npm test" } },
+                { "run": { "type": "command", "command": "npm test" }, "workingDirectory": "web" },
+            ],
+        }]))
+        .remove(0);
+
+        assert_eq!(job.steps[0].display_name(0), "Step 0: npm test");
+        assert_eq!(job.steps[1].display_name(1), "Step 1: npm test");
+    }
+
+    #[test]
+    fn retry_delay_backoff() {
+        let failed = |vertex: Option<&str>| JobStatus::Failed {
+            error: "exit code: 1".into(),
+            vertex: vertex.map(Into::into),
+            exit_code: Some(1),
+        };
+
+        let job = test_jobs(serde_json::json!([{
+            "retries": 2,
+            "retryBackoff": 5,
+            "steps": [{ "run": { "type": "command", "command": "npm test" }, "retries": 3 }],
+        }]))
+        .remove(0);
+
+        assert_eq!(job.retry_delay(&JobStatus::Success, 1), None);
+        assert_eq!(
+            job.retry_delay(&failed(None), 1),
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(
+            job.retry_delay(&failed(None), 2),
+            Some(Duration::from_secs(10))
+        );
+        assert_eq!(job.retry_delay(&failed(None), 3), None);
+
+        // The step has its own retries
+        assert_eq!(
+            job.retry_delay(&failed(Some("Step 0: npm test")), 3),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(job.retry_delay(&failed(Some("Step 0: npm test")), 4), None);
+
+        let timed_out = JobStatus::TimedOut {
+            step: None,
+            timeout: Duration::from_secs(60),
+        };
+        assert_eq!(job.retry_delay(&timed_out, 1), None);
+    }
+
+    #[test]
+    fn image_configuration_entrypoint_resets_cmd() {
+        let base_image: InspectInfo = serde_json::from_value(serde_json::json!({
+            "Config": { "Env": ["PATH=/usr/bin"], "Cmd": ["node"], "User": "node" },
+            "Architecture": "arm64",
+            "Os": "linux",
+        }))
+        .unwrap();
+        let job = test_jobs(serde_json::json!([{
+            "env": { "NODE_ENV": "production" },
+            "workingDirectory": "/srv",
+        }]))
+        .remove(0);
+
+        let options = ImageOptions {
+            entrypoint: Some(vec!["/srv/server".into()]),
+            ..Default::default()
+        };
+        let image_config = options.image_configuration(&job, &base_image).unwrap();
+        let config = image_config.config().as_ref().unwrap();
+        assert_eq!(config.entrypoint(), &Some(vec!["/srv/server".into()]));
+        assert_eq!(config.cmd(), &None);
+        assert_eq!(config.user().as_deref(), Some("node"));
+        assert_eq!(config.working_dir().as_deref(), Some("/srv"));
+        assert_eq!(
+            config.env(),
+            &Some(vec!["PATH=/usr/bin".into(), "NODE_ENV=production".into()])
+        );
+
+        // Without an entrypoint the command of the base image is kept
+        let image_config = ImageOptions::default()
+            .image_configuration(&job, &base_image)
+            .unwrap();
+        let config = image_config.config().as_ref().unwrap();
+        assert_eq!(config.entrypoint(), &None);
+        assert_eq!(config.cmd(), &Some(vec!["node".into()]));
+    }
+
+    #[test]
+    fn next_deadline_skips_timeouts_past_instant() {
+        let started = Instant::now();
+        let mut timeouts = Timeouts {
+            job: Some((started, Duration::from_secs(u64::MAX))),
+            steps: HashMap::new(),
+        };
+        let mut running_steps = HashMap::new();
+        assert_eq!(timeouts.next_deadline(&running_steps), None);
+
+        running_steps.insert(
+            "digest".to_owned(),
+            (
+                "Step 0: npm test".to_owned(),
+                started,
+                Duration::from_secs(60),
+            ),
+        );
+        timeouts.job = Some((started, Duration::from_secs(120)));
+        assert_eq!(
+            timeouts.next_deadline(&running_steps),
+            Some((
+                started + Duration::from_secs(60),
+                JobStatus::TimedOut {
+                    step: Some("Step 0: npm test".into()),
+                    timeout: Duration::from_secs(60),
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn expand_matrix_without_matrix() {
+        let job = test_jobs(serde_json::json!([{}])).remove(0);
+        assert_eq!(job.clone().expand_matrix(), vec![job]);
+    }
+
+    #[test]
+    fn expand_matrix_combinations() {
+        let job = test_jobs(serde_json::json!([{
+            "name": "test",
+            "env": { "CI": "true" },
+            "matrix": {
+                "image": ["node:18", "node:20"],
+                "platform": ["linux/amd64", "linux/arm64"],
+                "env": { "NODE_ENV": ["production"] },
+            },
+        }]))
+        .remove(0);
+
+        let expanded = job.clone().expand_matrix();
+        assert_eq!(
+            expanded
+                .iter()
+                .map(|job| job.name.clone().unwrap())
+                .collect::<Vec<_>>(),
+            vec![
+                "test (node:18, linux/amd64, NODE_ENV=production)",
+                "test (node:18, linux/arm64, NODE_ENV=production)",
+                "test (node:20, linux/amd64, NODE_ENV=production)",
+                "test (node:20, linux/arm64, NODE_ENV=production)",
+            ]
+        );
+
+        let last = expanded.last().unwrap();
+        assert_eq!(last.image, "node:20");
+        assert_eq!(last.platform.as_deref(), Some("linux/arm64"));
+        assert_eq!(last.env["NODE_ENV"], "production");
+        assert_eq!(last.env["CI"], "true");
+        assert_eq!(last.matrix, None);
+
+        let uuids: HashSet<_> = expanded.iter().map(|job| job.uuid).collect();
+        assert_eq!(uuids.len(), 4);
+        assert!(!uuids.contains(&job.uuid));
+    }
 }
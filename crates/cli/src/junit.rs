@@ -0,0 +1,264 @@
+use std::{fmt::Write, path::Path, time::Duration};
+
+use anyhow::{Context, Result};
+
+use crate::job::{JobReport, JobStatus, StepStatus};
+
+/// Write a JUnit XML report with a testsuite for every job and a testcase for every step
+pub fn write(path: &Path, pipeline_name: &str, reports: &[JobReport]) -> Result<()> {
+    std::fs::write(path, render(pipeline_name, reports))
+        .with_context(|| format!("Unable to write JUnit report to {}", path.display()))
+}
+
+fn render(pipeline_name: &str, reports: &[JobReport]) -> String {
+    let suites: Vec<Suite> = reports.iter().map(Suite::new).collect();
+
+    let mut out = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    writeln!(
+        out,
+        "<testsuites name=\"{}\" tests=\"{}\" failures=\"{}\" skipped=\"{}\" time=\"{}\">",
+        escape(pipeline_name),
+        suites.iter().map(|suite| suite.cases.len()).sum::<usize>(),
+        suites.iter().map(Suite::failures).sum::<usize>(),
+        suites.iter().map(Suite::skipped).sum::<usize>(),
+        seconds(reports.iter().map(|report| report.duration).sum()),
+    )
+    .unwrap();
+
+    for (report, suite) in reports.iter().zip(&suites) {
+        writeln!(
+            out,
+            "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" skipped=\"{}\" time=\"{}\">",
+            escape(&report.long_name),
+            suite.cases.len(),
+            suite.failures(),
+            suite.skipped(),
+            seconds(report.duration),
+        )
+        .unwrap();
+
+        for case in &suite.cases {
+            write!(
+                out,
+                "    <testcase name=\"{}\" classname=\"{}\" time=\"{}\"",
+                escape(case.name),
+                escape(&report.long_name),
+                seconds(case.duration),
+            )
+            .unwrap();
+
+            match &case.outcome {
+                Outcome::Passed => out.push_str("/>\n"),
+                Outcome::Failed { message, output } => writeln!(
+                    out,
+                    ">\n      <failure message=\"{}\">{}</failure>\n    </testcase>",
+                    escape(message),
+                    escape(output)
+                )
+                .unwrap(),
+                Outcome::Skipped { message: None } => {
+                    out.push_str(">\n      <skipped/>\n    </testcase>\n")
+                }
+                Outcome::Skipped {
+                    message: Some(message),
+                } => writeln!(
+                    out,
+                    ">\n      <skipped message=\"{}\"/>\n    </testcase>",
+                    escape(message)
+                )
+                .unwrap(),
+            }
+        }
+
+        out.push_str("  </testsuite>\n");
+    }
+
+    out.push_str("</testsuites>\n");
+    out
+}
+
+struct Suite<'a> {
+    cases: Vec<Case<'a>>,
+}
+
+struct Case<'a> {
+    name: &'a str,
+    duration: Duration,
+    outcome: Outcome<'a>,
+}
+
+enum Outcome<'a> {
+    Passed,
+    Failed { message: String, output: &'a str },
+    Skipped { message: Option<&'a str> },
+}
+
+impl<'a> Suite<'a> {
+    fn new(report: &'a JobReport) -> Self {
+        let skip_reason = match &report.status {
+            JobStatus::Skipped { reason } => Some(reason.as_str()),
+            _ => None,
+        };
+
+        let mut cases: Vec<Case> = report
+            .steps
+            .iter()
+            .map(|step| Case {
+                name: &step.name,
+                duration: step.duration,
+                outcome: match &step.status {
+                    StepStatus::Success => Outcome::Passed,
+                    StepStatus::Failed { error } => Outcome::Failed {
+                        message: error.clone(),
+                        output: &step.output,
+                    },
+                    StepStatus::Skipped => Outcome::Skipped {
+                        message: skip_reason,
+                    },
+                },
+            })
+            .collect();
+
+        // Jobs can fail outside of their steps, like when the image can not be pulled
+        let failed = !report.status.success() && skip_reason.is_none();
+        if failed
+            && !cases
+                .iter()
+                .any(|case| matches!(case.outcome, Outcome::Failed { .. }))
+        {
+            cases.push(Case {
+                name: &report.long_name,
+                duration: report.duration,
+                outcome: Outcome::Failed {
+                    message: report.status.to_string(),
+                    output: "",
+                },
+            });
+        }
+
+        Self { cases }
+    }
+
+    fn failures(&self) -> usize {
+        self.cases
+            .iter()
+            .filter(|case| matches!(case.outcome, Outcome::Failed { .. }))
+            .count()
+    }
+
+    fn skipped(&self) -> usize {
+        self.cases
+            .iter()
+            .filter(|case| matches!(case.outcome, Outcome::Skipped { .. }))
+            .count()
+    }
+}
+
+fn seconds(duration: Duration) -> String {
+    format!("{:.3}", duration.as_secs_f64())
+}
+
+/// Escape text for an attribute or element. Control characters other than whitespace are not
+/// allowed in XML so they are dropped, along with the color codes they start.
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\u{1b}' if chars.peek() == Some(&'[') => {
+                // An escape sequence ends with a character in `@..=~`
+                for c in chars.by_ref().skip(1) {
+                    if ('@'..='~').contains(&c) {
+                        break;
+                    }
+                }
+            }
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            '\t' | '\n' | '\r' => escaped.push(c),
+            c if c.is_control() => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::job::StepReport;
+
+    fn step(name: &str, status: StepStatus, output: &str) -> StepReport {
+        StepReport {
+            name: name.into(),
+            status,
+            cached: false,
+            duration: Duration::from_millis(1500),
+            output: output.into(),
+        }
+    }
+
+    #[test]
+    fn render_report() {
+        let reports = vec![
+            JobReport {
+                long_name: "test (node-0)".into(),
+                status: JobStatus::Failed {
+                    error: "exit code: 1".into(),
+                    vertex: Some("npm test".into()),
+                    exit_code: Some(1),
+                },
+                attempts: 1,
+                duration: Duration::from_secs(3),
+                steps: vec![
+                    step("npm ci", StepStatus::Success, ""),
+                    step(
+                        "npm test",
+                        StepStatus::Failed {
+                            error: "exit code: 1".into(),
+                        },
+                        "\u{1b}[31mexpected 1 < 2\u{1b}[0m\n",
+                    ),
+                    step("npm run lint", StepStatus::Skipped, ""),
+                ],
+                ignored: false,
+            },
+            JobReport {
+                long_name: "deploy".into(),
+                status: JobStatus::Skipped {
+                    reason: "test (node-0) failed".into(),
+                },
+                attempts: 0,
+                duration: Duration::ZERO,
+                steps: vec![step("deploy", StepStatus::Skipped, "")],
+                ignored: false,
+            },
+        ];
+
+        assert_eq!(
+            render("ci.ts", &reports),
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<testsuites name="ci.ts" tests="4" failures="1" skipped="2" time="3.000">
+  <testsuite name="test (node-0)" tests="3" failures="1" skipped="1" time="3.000">
+    <testcase name="npm ci" classname="test (node-0)" time="1.500"/>
+    <testcase name="npm test" classname="test (node-0)" time="1.500">
+      <failure message="exit code: 1">expected 1 &lt; 2
+</failure>
+    </testcase>
+    <testcase name="npm run lint" classname="test (node-0)" time="1.500">
+      <skipped/>
+    </testcase>
+  </testsuite>
+  <testsuite name="deploy" tests="1" failures="0" skipped="1" time="0.000">
+    <testcase name="deploy" classname="deploy" time="1.500">
+      <skipped message="test (node-0) failed"/>
+    </testcase>
+  </testsuite>
+</testsuites>
+"#
+        );
+    }
+}
@@ -1,15 +1,22 @@
 mod bin_deps;
 mod dag;
 mod debug;
+mod events;
 mod git;
+mod graph;
 mod job;
+mod junit;
 mod logging;
 mod oci;
+mod pattern;
+mod plan;
+mod report;
 #[cfg(feature = "telemetry")]
 mod telemetry;
 #[cfg(feature = "self-update")]
 mod update;
 mod util;
+mod validate;
 
 use anyhow::{bail, Context, Result};
 use buildkit_rs::{llb::Platform, reference::Reference, util::oci::OciBackend};
@@ -20,6 +27,7 @@ use oci::OciArgs;
 use once_cell::sync::Lazy;
 use std::{
     ffi::OsStr,
+    num::NonZeroUsize,
     path::{Path, PathBuf},
     process::{ExitCode, Stdio},
 };
@@ -31,15 +39,22 @@ use update::check_for_update;
 use url::Url;
 
 use ahash::{HashMap, HashMapExt};
-use clap::Parser;
+use clap::{Args, Parser};
 use owo_colors::{OwoColorize, Stream};
 use tokio::{io::AsyncWriteExt, process::Command};
 
 use crate::{
-    bin_deps::{buildctl_exe, deno_exe, BUILDKIT_VERSION},
-    dag::{invert_graph, topological_sort, Node},
+    bin_deps::{deno_exe, BUILDKIT_VERSION},
+    dag::{fan_out, reduce, Node, Scheduler},
+    events::Event,
     git::github_repo,
-    job::{CicadaType, InspectInfo, JobResolved, OnFail, Pipeline, TriggerOn},
+    graph::GraphFormat,
+    job::{
+        CicadaType, ImageOutput, InspectInfo, Job, JobReport, JobResolved, OnFail, Pipeline,
+        Trigger,
+    },
+    plan::{Plan, PlanFormat},
+    report::RunSummary,
 };
 
 // Transform from https://deno.land/x/cicada/mod.ts to https://deno.land/x/cicada@vX.Y.X/mod.ts
@@ -58,10 +73,15 @@ fn replace_with_version(s: &str) -> String {
         .into_owned()
 }
 
+/// The exit code when the pipeline is interrupted with Ctrl-C, the same as a shell uses for SIGINT
+const INTERRUPTED_EXIT_CODE: i32 = 130;
+
 static SERIALIZE_SCRIPT: Lazy<String> =
     Lazy::new(|| replace_with_version(include_str!("../scripts/serialize.ts")));
 static RUN_STEP_SCRIPT: Lazy<String> =
     Lazy::new(|| replace_with_version(include_str!("../scripts/run-step.ts")));
+static TRIGGER_SCRIPT: Lazy<String> =
+    Lazy::new(|| replace_with_version(include_str!("../scripts/trigger.ts")));
 
 static TEMPLATES: Lazy<Vec<String>> = Lazy::new(|| {
     vec![
@@ -114,7 +134,8 @@ where
     let mut deno_command = Command::new(deno_exe);
     deno_command
         .arg("run")
-        .arg(format!("--allow-read={proj_path}"))
+        // The out file can also hold the input of the script
+        .arg(format!("--allow-read={proj_path},{}", out_path.display()))
         .arg(format!("--allow-write={}", out_path.display()))
         .arg("--allow-net")
         .arg("--allow-env=CICADA_JOB");
@@ -155,6 +176,455 @@ where
     Ok(())
 }
 
+/// Ask which pipeline to use if none was passed
+fn select_pipeline(pipeline: Option<PathBuf>) -> Result<PathBuf> {
+    if let Some(pipeline) = pipeline {
+        return Ok(pipeline);
+    }
+
+    let cicada_dir = resolve_cicada_dir()?;
+
+    let mut pipelines = vec![];
+    for entry in std::fs::read_dir(cicada_dir)? {
+        let entry = entry?;
+        if entry.path().extension() == Some(OsStr::new("ts")) {
+            if let Some(pipeline) = entry.path().file_stem() {
+                pipelines.push(PathBuf::from(pipeline));
+            }
+        }
+    }
+
+    if pipelines.is_empty() {
+        anyhow::bail!("No pipelines found");
+    }
+
+    let i = dialoguer::Select::with_theme(&ColorfulTheme::default())
+        .with_prompt("Select a pipeline")
+        .items(
+            &pipelines
+                .iter()
+                .map(|p: &PathBuf| p.display())
+                .collect::<Vec<_>>(),
+        )
+        .default(0)
+        .interact_opt()
+        .map_err(|_| anyhow::anyhow!("Could not select pipeline"))?
+        .ok_or_else(|| anyhow::anyhow!("No pipeline selected"))?;
+
+    Ok(pipelines[i].clone())
+}
+
+/// The project directory is the parent of the `.cicada` directory the pipeline is in
+fn project_directory(pipeline_path: &Path) -> String {
+    pipeline_path
+        .parent()
+        .unwrap()
+        .parent()
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .to_owned()
+}
+
+/// Run the pipeline with deno to serialize it
+async fn serialize_pipeline(
+    deno_exe: &Path,
+    pipeline_url: &Url,
+    project_directory: &str,
+) -> Result<CicadaType> {
+    let tmp_file = tempfile::NamedTempFile::new()?;
+
+    run_deno_builder(
+        deno_exe,
+        &SERIALIZE_SCRIPT,
+        vec![
+            pipeline_url.to_string().as_ref(),
+            tmp_file.path().to_str().unwrap(),
+        ],
+        project_directory,
+        tmp_file.path(),
+    )
+    .await?;
+
+    // Read the output file
+    let out = std::fs::read_to_string(tmp_file.path())?;
+
+    Ok(serde_json::from_str(&out)?)
+}
+
+/// Check the trigger against the git event from `CICADA_GIT_EVENT` and `CICADA_BASE_REF`,
+/// if the pipeline should not run the reason is returned
+async fn skip_reason(
+    on: Option<Trigger>,
+    deno_exe: &Path,
+    pipeline_url: &Url,
+    project_directory: &str,
+) -> Result<Option<String>> {
+    let (git_event, base_ref) = match (
+        std::env::var("CICADA_GIT_EVENT"),
+        std::env::var("CICADA_BASE_REF"),
+    ) {
+        (Ok(git_event), Ok(base_ref)) => (git_event, base_ref),
+        (Ok(_), Err(_)) | (Err(_), Ok(_)) => {
+            anyhow::bail!("CICADA_GIT_EVENT and CICADA_BASE_REF must be set together")
+        }
+        (Err(_), Err(_)) => return Ok(None),
+    };
+
+    match on {
+        Some(Trigger::Options {
+            push,
+            pull_request,
+            tag,
+            schedule,
+            manual,
+            paths,
+            paths_ignore,
+        }) => {
+            let (event, trigger_on) = match &*git_event {
+                "push" => ("push", push),
+                "pull_request" => ("pull_request", pull_request),
+                "tag" => ("tag", tag),
+                "schedule" => ("schedule", schedule),
+                "manual" | "workflow_dispatch" => ("manual", manual),
+                _ => {
+                    warn!(
+                        "Unknown git event {}, expected one of push, pull_request, tag, schedule, manual or workflow_dispatch",
+                        git_event.bold()
+                    );
+                    return Ok(Some(format!(
+                        "the git event {} is unknown",
+                        git_event.bold()
+                    )));
+                }
+            };
+
+            if let Some(trigger_on) = &trigger_on {
+                if !trigger_on.matches(&base_ref)? {
+                    return Ok(Some(format!(
+                        "{} {} is not in {}: {:?}",
+                        if event == "tag" { "tag" } else { "branch" },
+                        base_ref.bold(),
+                        event.bold(),
+                        trigger_on
+                    )));
+                }
+            }
+
+            // Tags, schedules and manual runs do not have changes to filter on
+            if matches!(event, "push" | "pull_request")
+                && (!paths.is_empty() || !paths_ignore.is_empty())
+            {
+                match git::changed_files(&base_ref).await {
+                    Ok(changed_files) => {
+                        if !job::paths_match(&changed_files, &paths, &paths_ignore)? {
+                            return Ok(Some(format!(
+                                "none of the {} changed files match the paths filter",
+                                changed_files.len()
+                            )));
+                        }
+                    }
+                    // A shallow clone may not have the commits, running is safer than skipping
+                    Err(err) => warn!(
+                        "Unable to get changed files for the paths filter, running the pipeline: {err}"
+                    ),
+                }
+            }
+
+            Ok(None)
+        }
+        Some(Trigger::DenoFunction) => {
+            let changed_files = git::changed_files(&base_ref).await.unwrap_or_else(|err| {
+                warn!("Unable to get changed files for the trigger: {err}");
+                vec![]
+            });
+
+            let context = serde_json::json!({
+                "event": git_event,
+                "baseRef": base_ref,
+                "changedFiles": changed_files,
+                "env": std::env::vars().collect::<HashMap<_, _>>(),
+            });
+
+            // The context has the whole environment so it is passed in the file the result is
+            // written to, arguments can be read by other users and have a size limit
+            let tmp_file = tempfile::NamedTempFile::new()?;
+            std::fs::write(tmp_file.path(), context.to_string())?;
+
+            run_deno_builder(
+                deno_exe,
+                &TRIGGER_SCRIPT,
+                vec![
+                    pipeline_url.to_string(),
+                    tmp_file.path().to_str().unwrap().to_owned(),
+                ],
+                project_directory,
+                tmp_file.path(),
+            )
+            .await
+            .context("Failed to run the trigger function")?;
+
+            let should_run: bool = serde_json::from_str(&std::fs::read_to_string(tmp_file.path())?)
+                .context("Unable to deserialize trigger function result")?;
+
+            Ok((!should_run).then(|| {
+                format!(
+                    "the trigger function returned false for {} on {}",
+                    git_event.bold(),
+                    base_ref.bold()
+                )
+            }))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Where the secrets of a run come from
+#[derive(Args, Debug, Clone)]
+struct SecretArgs {
+    /// Name of the secret to use, these come from environment variables
+    ///
+    /// The CLI will also look for a .env file
+    #[arg(short, long)]
+    secret: Vec<String>,
+
+    /// Do not load .env file
+    #[arg(long)]
+    no_dotenv: bool,
+
+    /// Load a custom .env file
+    ///
+    /// This will override the default .env lookup
+    #[arg(long)]
+    dotenv: Option<PathBuf>,
+
+    /// Load secrets from a json file
+    ///
+    /// They should look like this:
+    /// `{
+    ///     "KEY": "VALUE",
+    ///     "KEY2": "VALUE2"
+    /// }`
+    #[arg(long)]
+    secrets_json: Option<PathBuf>,
+}
+
+impl SecretArgs {
+    fn load(self) -> Result<Vec<(String, String)>> {
+        let SecretArgs {
+            secret,
+            no_dotenv,
+            dotenv,
+            secrets_json,
+        } = self;
+
+        let mut all_secrets: Vec<(String, String)> = vec![];
+
+        // Look for the secret in the environment or error
+        for secret in secret {
+            all_secrets.push((
+                secret.clone(),
+                std::env::var(&secret)
+                    .with_context(|| format!("Could not find secret in environment: {secret}"))?,
+            ));
+        }
+
+        if !no_dotenv {
+            // Load the .env file if it exists
+            let iter =
+                match dotenv {
+                    Some(path) => Some(dotenvy::from_path_iter(&path).with_context(|| {
+                        format!("Could not load dotenv file: {}", path.display())
+                    })?),
+                    None => dotenvy::dotenv_iter().ok(),
+                };
+
+            if let Some(iter) = iter {
+                for (key, value) in iter.flatten() {
+                    all_secrets.push((key, value));
+                }
+            }
+        }
+
+        // Load the secrets json file if it exists
+        if let Some(path) = secrets_json {
+            let secrets: HashMap<String, String> =
+                serde_json::from_str(&std::fs::read_to_string(&path).with_context(|| {
+                    format!("Could not load secrets json file: {}", path.display())
+                })?)
+                .with_context(|| {
+                    format!("Could not parse secrets json file: {}", path.display())
+                })?;
+
+            for (key, value) in secrets {
+                all_secrets.push((key, value));
+            }
+        }
+
+        Ok(all_secrets)
+    }
+}
+
+/// The names of the secrets of a run
+fn secret_names(secrets: &[(String, String)]) -> ahash::HashSet<String> {
+    secrets.iter().map(|(key, _)| key.clone()).collect()
+}
+
+/// Log every problem found in a pipeline and fail if there are any
+fn check_pipeline(problems: Vec<validate::Problem>) -> Result<()> {
+    for problem in &problems {
+        error!("{problem}");
+    }
+
+    match problems.len() {
+        0 => Ok(()),
+        1 => anyhow::bail!("Found 1 problem in the pipeline"),
+        count => anyhow::bail!("Found {count} problems in the pipeline"),
+    }
+}
+
+/// Filters for the jobs to run by name
+#[derive(Args, Debug, Clone)]
+struct JobFilters {
+    /// Only run jobs with a name matching this pattern, can be passed multiple times
+    ///
+    /// The jobs they depend on are also run unless `--no-deps` is passed
+    #[arg(long, value_name = "NAME")]
+    job: Vec<String>,
+
+    /// Do not run jobs with a name matching this pattern, can be passed multiple times
+    #[arg(long, value_name = "NAME")]
+    skip_job: Vec<String>,
+
+    /// Do not run the dependencies of the jobs selected with `--job`
+    #[arg(long, requires = "job")]
+    no_deps: bool,
+}
+
+/// Expand matrix jobs, each expansion keeps the index of its job in the pipeline so `cicada step`
+/// can still find it
+fn expand_jobs(jobs: Vec<(usize, Job)>) -> Vec<(usize, Job)> {
+    let mut expansions: HashMap<uuid::Uuid, Vec<uuid::Uuid>> = HashMap::new();
+    let mut expanded_jobs = vec![];
+    for (index, job) in jobs {
+        let uuid = job.uuid;
+        let jobs = job.expand_matrix();
+        expansions.insert(uuid, jobs.iter().map(|job| job.uuid).collect());
+        expanded_jobs.extend(jobs.into_iter().map(|job| (index, job)));
+    }
+
+    for (_, job) in &mut expanded_jobs {
+        job.depends_on = fan_out(&job.depends_on, &expansions);
+    }
+
+    expanded_jobs
+}
+
+/// Filter the jobs of a pipeline by name, the dependencies of selected jobs are kept unless `no_deps` is set
+fn filter_jobs(jobs: Vec<(usize, Job)>, filters: &JobFilters) -> Result<Vec<(usize, Job)>> {
+    let JobFilters {
+        job: job_filter,
+        skip_job,
+        no_deps,
+    } = filters;
+
+    if job_filter.is_empty() && skip_job.is_empty() {
+        return Ok(jobs);
+    }
+
+    if let Some(filter) = job_filter
+        .iter()
+        .chain(skip_job)
+        .find(|f| f.starts_with('!'))
+    {
+        bail!(
+            "Job filters can not exclude with {}, use {} instead: {}",
+            "!".bold(),
+            "--skip-job".bold(),
+            filter.bold()
+        );
+    }
+
+    let name_matches = |filter: &str, job: &Job| -> Result<bool> {
+        let pattern = pattern::Pattern::new(filter)?;
+        Ok(job
+            .name
+            .as_deref()
+            .is_some_and(|name| pattern.is_match(name)))
+    };
+
+    let mut selected: ahash::HashSet<uuid::Uuid> = ahash::HashSet::default();
+    for filter in job_filter {
+        let mut matched = false;
+        for (_, job) in &jobs {
+            if name_matches(filter, job)? {
+                selected.insert(job.uuid);
+                matched = true;
+            }
+        }
+
+        if !matched {
+            bail!("No jobs have a name matching {}", filter.bold());
+        }
+    }
+    if job_filter.is_empty() {
+        selected = jobs.iter().map(|(_, job)| job.uuid).collect();
+    }
+
+    let mut excluded: ahash::HashSet<uuid::Uuid> = ahash::HashSet::default();
+    for filter in skip_job {
+        for (_, job) in &jobs {
+            if name_matches(filter, job)? {
+                excluded.insert(job.uuid);
+            }
+        }
+    }
+
+    let nodes: Vec<Node> = jobs
+        .iter()
+        .map(|(_, job)| Node::new(job.uuid, job.depends_on.clone()))
+        .collect();
+    let reduced: HashMap<uuid::Uuid, Node> = reduce(&nodes, &selected, &excluded, !*no_deps)
+        .into_iter()
+        .map(|node| (node.id(), node))
+        .collect();
+
+    Ok(jobs
+        .into_iter()
+        .filter_map(|(index, mut job)| {
+            let node = reduced.get(&job.uuid)?;
+            job.depends_on = node.edges().to_vec();
+            Some((index, job))
+        })
+        .collect())
+}
+
+/// Skip the jobs that depend on a failed job
+fn skip_dependents(
+    scheduler: &mut Scheduler,
+    jobs: &mut HashMap<uuid::Uuid, (usize, JobResolved)>,
+    summary: &mut RunSummary,
+    failed: uuid::Uuid,
+    failed_name: &str,
+) {
+    for uuid in scheduler.skip_dependents(failed) {
+        if let Some((job_index, job)) = jobs.remove(&uuid) {
+            let long_name = job.long_name(job_index);
+            warn!("Skipping {long_name} because {failed_name} failed");
+            push_report(
+                summary,
+                JobReport::skipped(long_name, &job.job, format!("{failed_name} failed")),
+            );
+        }
+    }
+}
+
+/// Keep the report of a finished job and send its event
+fn push_report(summary: &mut RunSummary, report: JobReport) {
+    events::emit(Event::job_finished(&report));
+    summary.reports.push(report);
+}
+
 /// Check that oci backend is working before doing anything else for clean error messages
 async fn runtime_checks(oci: &OciBackend) -> anyhow::Result<()> {
     if std::env::var_os("CICADA_SKIP_CHECKS").is_some() {
@@ -223,31 +693,8 @@ enum Commands {
         /// Path to the pipeline file
         pipeline: Option<PathBuf>,
 
-        /// Name of the secret to use, these come from environment variables
-        ///
-        /// The CLI will also look for a .env file
-        #[arg(short, long)]
-        secret: Vec<String>,
-
-        /// Do not load .env file
-        #[arg(long)]
-        no_dotenv: bool,
-
-        /// Load a custom .env file
-        ///
-        /// This will override the default .env lookup
-        #[arg(long)]
-        dotenv: Option<PathBuf>,
-
-        /// Load secrets from a json file
-        ///
-        /// They should look like this:
-        /// `{
-        ///     "KEY": "VALUE",
-        ///     "KEY2": "VALUE2"
-        /// }`
-        #[arg(long)]
-        secrets_json: Option<PathBuf>,
+        #[command(flatten)]
+        secret_args: SecretArgs,
 
         /// A custom dockerfile to load the cicada bin from
         ///
@@ -266,12 +713,83 @@ enum Commands {
         #[arg(long, hide = true)]
         gh_action_cache: bool,
 
-        /// Sets the default platform to use
+        /// Sets the platform for jobs that do not set their own
         ///
         /// Example: `linux/amd64` or `linux/arm64`
         #[arg(long, env = "CICADA_PLATFORM", default_value = "linux/amd64")]
         platform: Platform,
+
+        /// Write the image from an image pipeline as an OCI image layout
+        ///
+        /// Paths ending in `.tar` are written as a tarball, otherwise as a directory
+        #[arg(long)]
+        output: Option<PathBuf>,
+
+        /// Push the image from an image pipeline to a registry
+        ///
+        /// The image is pushed from the buildkitd container, so `localhost` is the container and
+        /// not this machine. A registry on this machine is reachable at `host.docker.internal` on
+        /// Docker Desktop, or at the docker bridge gateway (usually `172.17.0.1`) on linux.
+        ///
+        /// Example: `ghcr.io/my-org/my-app:latest` or `host.docker.internal:5000/my-app:latest`
+        #[arg(long)]
+        push: Option<String>,
+
+        /// Allow pushing to a registry over plain http
+        #[arg(long, requires = "push")]
+        push_insecure: bool,
+
+        /// Keep running the jobs that do not depend on a failed job
+        #[arg(long)]
+        keep_going: bool,
+
+        /// The most jobs to run at the same time, overrides `maxParallel` of the pipeline
+        #[arg(long, short)]
+        jobs: Option<NonZeroUsize>,
+
+        /// Write a JUnit XML report of the run to this file
+        #[arg(long, value_name = "PATH")]
+        junit: Option<PathBuf>,
+
+        /// Write the progress of the run as newline delimited JSON events to this file, or to an
+        /// open file descriptor as `fd:<n>`
+        #[arg(long, value_name = "PATH|fd:N")]
+        events: Option<String>,
+
+        #[command(flatten)]
+        job_filters: JobFilters,
     },
+    /// Check a pipeline for problems without running it
+    Validate {
+        /// Path to the pipeline file
+        pipeline: Option<PathBuf>,
+
+        #[command(flatten)]
+        secret_args: SecretArgs,
+    },
+    /// Print the jobs of a pipeline and their dependencies as a graph
+    Graph {
+        /// Path to the pipeline file
+        pipeline: Option<PathBuf>,
+
+        /// The output format
+        #[arg(long, value_enum, default_value_t = GraphFormat::Dot)]
+        format: GraphFormat,
+    },
+    /// Print what running a pipeline would do without running it
+    Plan {
+        /// Path to the pipeline file
+        pipeline: Option<PathBuf>,
+
+        /// The output format
+        #[arg(long, value_enum, default_value_t = PlanFormat::Human)]
+        format: PlanFormat,
+
+        #[command(flatten)]
+        job_filters: JobFilters,
+    },
+    /// Print the JSON Schema of the pipelines serialized for the CLI
+    Schema,
     /// Run a step in a cicada workflow
     #[command(hide = true)]
     Step { workflow: usize, step: usize },
@@ -307,16 +825,25 @@ impl Commands {
         match self {
             Commands::Run {
                 pipeline,
-                secret,
-                no_dotenv,
-                dotenv,
-                secrets_json,
+                secret_args,
                 cicada_dockerfile,
                 oci_args,
                 no_cache,
                 gh_action_cache,
                 platform,
+                output,
+                push,
+                push_insecure,
+                keep_going,
+                jobs: max_parallel,
+                junit,
+                events,
+                job_filters,
             } => {
+                if let Some(events) = &events {
+                    events::init(events)?;
+                }
+
                 let oci_backend = oci_args.oci_backend();
 
                 #[cfg(feature = "self-update")]
@@ -325,41 +852,7 @@ impl Commands {
                 #[cfg(not(feature = "self-update"))]
                 runtime_checks(&oci_backend).await?;
 
-                let pipeline = match pipeline {
-                    Some(pipeline) => pipeline,
-                    None => {
-                        let cicada_dir = resolve_cicada_dir()?;
-
-                        let mut pipelines = vec![];
-                        for entry in std::fs::read_dir(cicada_dir)? {
-                            let entry = entry?;
-                            if entry.path().extension() == Some(OsStr::new("ts")) {
-                                if let Some(pipeline) = entry.path().file_stem() {
-                                    pipelines.push(PathBuf::from(pipeline));
-                                }
-                            }
-                        }
-
-                        if pipelines.is_empty() {
-                            anyhow::bail!("No pipelines found");
-                        }
-
-                        let i = dialoguer::Select::with_theme(&ColorfulTheme::default())
-                            .with_prompt("Select a pipeline to run")
-                            .items(
-                                &pipelines
-                                    .iter()
-                                    .map(|p: &PathBuf| p.display())
-                                    .collect::<Vec<_>>(),
-                            )
-                            .default(0)
-                            .interact_opt()
-                            .map_err(|_| anyhow::anyhow!("Could not select pipeline"))?
-                            .ok_or_else(|| anyhow::anyhow!("No pipeline selected"))?;
-
-                        pipelines[i].clone()
-                    }
-                };
+                let pipeline = select_pipeline(pipeline)?;
 
                 info!(
                     "\n{}{}\n{}{}\n",
@@ -373,7 +866,6 @@ impl Commands {
                 eprintln!();
 
                 let deno_exe = deno_exe().await?;
-                let buildctl_exe = buildctl_exe().await?;
 
                 let cicada_image = if let Some(cicada_dockerfile) = cicada_dockerfile {
                     let tag = format!(
@@ -419,14 +911,7 @@ impl Commands {
                     .unwrap()
                     .to_owned();
 
-                let project_directory = pipeline_path
-                    .parent()
-                    .unwrap()
-                    .parent()
-                    .unwrap()
-                    .to_str()
-                    .unwrap()
-                    .to_owned();
+                let project_directory = project_directory(&pipeline_path);
                 let pipeline_url = Url::from_file_path(&pipeline_path)
                     .map_err(|_| anyhow::anyhow!("Unable to convert pipeline path to URL"))?;
 
@@ -434,77 +919,70 @@ impl Commands {
 
                 info!("Building pipeline: {}", pipeline_path.display().bold());
 
-                let out = {
-                    let tmp_file = tempfile::NamedTempFile::new()?;
-
-                    run_deno_builder(
-                        &deno_exe,
-                        &SERIALIZE_SCRIPT,
-                        vec![
-                            pipeline_url.to_string().as_ref(),
-                            tmp_file.path().to_str().unwrap(),
-                        ],
-                        &project_directory,
-                        tmp_file.path(),
-                    )
-                    .await?;
+                let (pipeline, mut image_options) =
+                    match serialize_pipeline(&deno_exe, &pipeline_url, &project_directory).await? {
+                        CicadaType::Pipeline(pipeline) => (pipeline, None),
+                        // Every expansion would be exported to the same image
+                        CicadaType::Image(image) if image.job.matrix.is_some() => {
+                            anyhow::bail!("An image can not have a {}", "matrix".bold())
+                        }
+                        CicadaType::Image(image) => (
+                            Pipeline {
+                                jobs: vec![image.job],
+                                ..Default::default()
+                            },
+                            Some(image.options),
+                        ),
+                    };
 
-                    // Read the output file
-                    std::fs::read_to_string(tmp_file.path())?
-                };
+                // Only the structure is checked until the trigger passes and the jobs are selected,
+                // a run that is skipped or does not select a job does not need its secrets
+                let jobs: Vec<(usize, Job)> = pipeline.jobs.into_iter().enumerate().collect();
+                check_pipeline(validate::validate_graph(&jobs))?;
 
-                let pipeline = match serde_json::from_str::<CicadaType>(&out)? {
-                    CicadaType::Pipeline(pipeline) => pipeline,
-                    CicadaType::Image(image) => Pipeline {
-                        jobs: vec![image],
-                        ..Default::default()
-                    },
-                };
+                let max_parallel = max_parallel.or(pipeline.max_parallel);
 
-                // Check if we should run this pipeline based on the git event
-                match (
-                    std::env::var("CICADA_GIT_EVENT"),
-                    std::env::var("CICADA_BASE_REF"),
-                ) {
-                    (Ok(git_event), Ok(base_ref)) => match pipeline.on {
-                        Some(job::Trigger::Options { push, pull_request }) => match &*git_event {
-                            "pull_request" => {
-                                if let Some(TriggerOn::Branches { branches }) = &pull_request {
-                                    if !branches.contains(&base_ref) {
-                                        info!(
-                                        "Skipping pipeline because branch {} is not in {}: {:?}",
-                                        base_ref.bold(),
-                                        "pull_request".bold(),
-                                        pull_request
-                                    );
-                                        std::process::exit(2);
-                                    }
-                                }
-                            }
-                            "push" => {
-                                if let Some(TriggerOn::Branches { branches }) = &push {
-                                    if !branches.contains(&base_ref) {
-                                        info!(
-                                        "Skipping pipeline because branch {} is not in {}: {:?}",
-                                        base_ref.bold(),
-                                        "push".bold(),
-                                        push
-                                    );
-                                        std::process::exit(2);
-                                    }
-                                }
-                            }
-                            _ => {}
-                        },
-                        Some(job::Trigger::DenoFunction) => {
-                            anyhow::bail!("TypeScript trigger functions are unimplemented")
-                        }
-                        None => {}
-                    },
-                    (Ok(_), Err(_)) | (Err(_), Ok(_)) => {
-                        anyhow::bail!("CICADA_GIT_EVENT and CICADA_BASE_REF must be set together")
+                let mut image_outputs = vec![ImageOutput::Load];
+
+                if let Some(output) = output {
+                    image_outputs.push(ImageOutput::oci_layout(output));
+                }
+
+                if let Some(push) = push {
+                    let mut reference =
+                        Reference::parse_normalized_named(&push).with_context(|| {
+                            format!("Unable to parse push reference: {}", push.bold())
+                        })?;
+
+                    if reference.tag.is_none() && reference.digest.is_none() {
+                        reference.tag = Some("latest".into());
                     }
-                    (Err(_), Err(_)) => {}
+
+                    image_outputs.push(ImageOutput::Push {
+                        reference: reference.to_string(),
+                        insecure: push_insecure,
+                    });
+                }
+
+                if image_options.is_none() && image_outputs.len() > 1 {
+                    anyhow::bail!(
+                        "{} and {} can only be used with an image pipeline",
+                        "--output".bold(),
+                        "--push".bold()
+                    );
+                }
+
+                // Check if we should run this pipeline based on the git event
+                if let Some(reason) = skip_reason(
+                    pipeline.on.clone(),
+                    &deno_exe,
+                    &pipeline_url,
+                    &project_directory,
+                )
+                .await?
+                {
+                    info!("Skipping pipeline because {reason}");
+                    std::process::exit(2);
                 }
 
                 info!(trigger = true);
@@ -521,16 +999,34 @@ impl Commands {
                         TrackEvent::PipelineExecuted {
                             pipeline_name,
                             pipeline_length,
-                            job_count: pipeline.jobs.len(),
-                            step_count: pipeline
-                                .jobs
-                                .iter()
-                                .fold(0, |acc, job| acc + job.steps.len()),
+                            job_count: jobs.len(),
+                            step_count: jobs.iter().fold(0, |acc, (_, job)| acc + job.steps.len()),
                         }
                         .post(),
                     )
                 });
 
+                let all_secrets = secret_args.load()?;
+
+                // Only run the jobs selected by name, the index of each job in the pipeline is kept
+                let expanded_jobs = expand_jobs(filter_jobs(jobs, &job_filters)?);
+                check_pipeline(validate::validate_jobs(
+                    &expanded_jobs,
+                    &secret_names(&all_secrets),
+                ))?;
+
+                // Artifacts are exported to `.cicada/artifacts/<run>/<job>`
+                let artifacts_root = pipeline_path.parent().unwrap().join("artifacts");
+                if expanded_jobs.iter().any(|(_, job)| job.has_artifacts())
+                    && !artifacts_root.exists()
+                {
+                    std::fs::create_dir_all(&artifacts_root)?;
+                    // Keep artifacts out of version control
+                    std::fs::write(artifacts_root.join(".gitignore"), "*\n")?;
+                }
+                let artifacts_directory = artifacts_root
+                    .join(time::OffsetDateTime::now_utc().unix_timestamp().to_string());
+
                 let inspect_output = Command::new(oci_backend.as_str())
                     .args([
                         "inspect",
@@ -583,9 +1079,19 @@ impl Commands {
                 }
 
                 // Populate the jobs with `docker inspect` data
-                let mut populated_jobs: Vec<JobResolved> = vec![];
-                let mut image_info_map: HashMap<String, InspectInfo> = HashMap::new();
-                for job in pipeline.jobs {
+                let mut populated_jobs: Vec<(usize, JobResolved)> = vec![];
+                let mut image_info_map: HashMap<(String, String), InspectInfo> = HashMap::new();
+                for (index, job) in expanded_jobs {
+                    let job_platform = match &job.platform {
+                        Some(job_platform) => job_platform.parse::<Platform>().map_err(|err| {
+                            anyhow::anyhow!(
+                                "Unable to parse platform {} for job {}: {err}",
+                                job_platform.bold(),
+                                job.name.as_deref().unwrap_or(&job.image).bold()
+                            )
+                        })?,
+                        None => platform.clone(),
+                    };
                     let mut image_reference = Reference::parse_normalized_named(&job.image)
                         .with_context(|| {
                             format!(
@@ -600,7 +1106,8 @@ impl Commands {
 
                     let image_reference_str = image_reference.to_string();
 
-                    let image_info = match image_info_map.get(&image_reference_str) {
+                    let image_info_key = (image_reference_str.clone(), job_platform.to_string());
+                    let image_info = match image_info_map.get(&image_info_key) {
                         Some(inspect_info) => inspect_info.clone(),
                         None => {
                             info!("Pulling image: {}", image_reference_str.bold());
@@ -611,7 +1118,7 @@ impl Commands {
                                     "pull",
                                     &image_reference_str,
                                     "--platform",
-                                    &platform.to_string(),
+                                    &job_platform.to_string(),
                                 ])
                                 .spawn()?;
 
@@ -649,129 +1156,242 @@ impl Commands {
                                 serde_json::from_slice(&docker_inspect_output.stdout)
                                     .context("Unable to deserialize image info")?;
 
-                            image_info_map.insert(image_reference_str.clone(), image_info.clone());
+                            image_info_map.insert(image_info_key, image_info.clone());
 
                             image_info
                         }
                     };
 
-                    populated_jobs.push(JobResolved {
-                        job: Box::new(job),
-                        image_info: Box::new(image_info),
-                        image_reference,
-                    });
+                    populated_jobs.push((
+                        index,
+                        JobResolved {
+                            job: Box::new(job),
+                            image_info: Box::new(image_info),
+                            image_reference,
+                            // An image only has a single job
+                            image_options: image_options.take().map(Box::new),
+                            image_outputs: image_outputs.clone(),
+                            dependency_artifacts: vec![],
+                            platform: job_platform,
+                        },
+                    ));
                 }
 
                 let mut jobs = populated_jobs
                     .into_iter()
-                    .enumerate()
                     .map(|(index, job)| (job.job.uuid, (index, job)))
                     .collect::<HashMap<_, _>>();
 
-                let mut all_secrets: Vec<(String, String)> = vec![];
-
-                // Look for the secret in the environment or error
-                for secret in secret {
-                    all_secrets.push((
-                        secret.clone(),
-                        std::env::var(&secret).with_context(|| {
-                            format!("Could not find secret in environment: {secret}")
-                        })?,
-                    ));
-                }
-
-                if !no_dotenv {
-                    // Load the .env file if it exists
-                    let iter = match dotenv {
-                        Some(path) => Some(dotenvy::from_path_iter(&path).with_context(|| {
-                            format!("Could not load dotenv file: {}", path.display())
-                        })?),
-                        None => dotenvy::dotenv_iter().ok(),
-                    };
-
-                    if let Some(iter) = iter {
-                        for (key, value) in iter.flatten() {
-                            all_secrets.push((key, value));
-                        }
-                    }
-                }
-
-                // Load the secrets json file if it exists
-                if let Some(path) = secrets_json {
-                    let secrets: HashMap<String, String> =
-                        serde_json::from_str(&std::fs::read_to_string(&path).with_context(
-                            || format!("Could not load secrets json file: {}", path.display()),
-                        )?)
-                        .with_context(|| {
-                            format!("Could not parse secrets json file: {}", path.display())
-                        })?;
+                // Jobs get the artifacts of the jobs they depend on
+                let job_artifacts_directories: HashMap<uuid::Uuid, PathBuf> = jobs
+                    .iter()
+                    .filter(|(_, (_, job))| job.job.has_artifacts())
+                    .map(|(uuid, (index, job))| {
+                        (*uuid, job.artifacts_directory(&artifacts_directory, *index))
+                    })
+                    .collect();
 
-                    for (key, value) in secrets {
-                        all_secrets.push((key, value));
-                    }
+                for (_, job) in jobs.values_mut() {
+                    job.dependency_artifacts = job
+                        .job
+                        .depends_on
+                        .iter()
+                        .filter_map(|uuid| job_artifacts_directories.get(uuid).cloned())
+                        .collect();
                 }
 
-                let nodes: Vec<Node> = jobs
+                let mut nodes: Vec<(usize, String, Node)> = jobs
                     .values()
-                    .map(|(_, job)| Node::new(job.job.uuid, job.job.depends_on.clone()))
+                    .map(|(job_index, job)| {
+                        (
+                            *job_index,
+                            job.long_name(*job_index),
+                            Node::new(job.job.uuid, job.job.depends_on.clone()),
+                        )
+                    })
                     .collect();
-                let graph = topological_sort(&invert_graph(&nodes))?;
-
-                let mut exit_code = 0;
-                'run_groups: for run_group in graph {
-                    match futures::future::try_join_all(run_group.into_iter().map(|job| {
-                        let (job_index, job) = jobs.remove(&job).unwrap();
+                // Start jobs in the order of the pipeline
+                nodes.sort_by(|(a_index, a_name, _), (b_index, b_name, _)| {
+                    a_index.cmp(b_index).then_with(|| a_name.cmp(b_name))
+                });
+                let pipeline_started = std::time::Instant::now();
+                events::emit(Event::PipelineStarted {
+                    pipeline: &pipeline_name,
+                    jobs: nodes.len(),
+                });
+                for (_, long_name, node) in &nodes {
+                    events::emit(Event::JobQueued {
+                        job: long_name,
+                        depends_on: nodes
+                            .iter()
+                            .filter(|(_, _, dependency)| node.edges().contains(&dependency.id()))
+                            .map(|(_, long_name, _)| long_name.as_str())
+                            .collect(),
+                    });
+                }
 
-                        let span = info_span!("job", job_name = job.display_name(job_index));
-                        let _enter = span.enter();
+                let nodes: Vec<Node> = nodes.into_iter().map(|(_, _, node)| node).collect();
+                let mut scheduler = Scheduler::new(&nodes)?;
 
-                        tokio::spawn(
-                            job.solve(
+                let mut exit_code = 0;
+                // Once a job fails no new jobs are started, the running jobs are left to finish
+                let mut stopping = false;
+                let mut summary = RunSummary::default();
+                let mut running = tokio::task::JoinSet::new();
+                let mut running_jobs: HashMap<uuid::Uuid, String> = HashMap::new();
+                // One listener for the whole run so a Ctrl-C between iterations is not missed
+                let interrupt = tokio::signal::ctrl_c();
+                tokio::pin!(interrupt);
+                loop {
+                    if !stopping {
+                        let limit = max_parallel.map_or(usize::MAX, |max_parallel| {
+                            max_parallel.get().saturating_sub(running.len())
+                        });
+                        for uuid in scheduler.ready(limit) {
+                            let (job_index, job) = jobs.remove(&uuid).unwrap();
+
+                            let span = info_span!("job", job_name = job.display_name(job_index));
+                            let _enter = span.enter();
+
+                            running_jobs.insert(uuid, job.long_name(job_index));
+                            events::emit(Event::JobStarted {
+                                job: &running_jobs[&uuid],
+                            });
+
+                            let solve = job.solve(
                                 job_index,
                                 github.clone(),
                                 pipeline_name.clone(),
                                 project_directory.clone(),
                                 all_secrets.clone(),
                                 cicada_image.clone(),
-                                buildctl_exe.clone(),
                                 no_cache,
                                 gh_action_cache,
                                 oci_backend,
-                                platform.clone(),
-                            )
-                            .in_current_span(),
-                        )
-                    }))
-                    .await
-                    {
-                        Ok(results) => {
-                            for result in results {
-                                match result {
-                                    Ok((long_name, exit_status, job)) => match job.job.on_fail {
-                                        Some(OnFail::Ignore) if !exit_status.success() => {
-                                            warn!("{long_name} failed with status {exit_status} but was ignored");
-                                        }
-                                        Some(OnFail::Stop) | None if !exit_status.success() => {
-                                            error!("Build failed for {long_name} with status {exit_status}");
-                                            exit_code = 1;
-                                            break 'run_groups;
-                                        }
-                                        _ => {
-                                            info!("{long_name} finished with status {exit_status}");
-                                        }
-                                    },
-                                    Err(err) => {
-                                        error!("{err}");
-                                        exit_code = 1;
-                                        break 'run_groups;
-                                    }
-                                }
+                                artifacts_directory.clone(),
+                            );
+                            running.spawn(async move { (uuid, solve.await) }.in_current_span());
+                        }
+                    }
+
+                    let result = tokio::select! {
+                        result = running.join_next() => match result {
+                            Some(result) => result,
+                            None => break,
+                        },
+                        _ = &mut interrupt => {
+                            // Aborting the jobs drops their solves which cancels them in buildkit
+                            running.abort_all();
+
+                            error!("Interrupted, cancelled {} running jobs", running_jobs.len());
+                            for long_name in running_jobs.values() {
+                                error!("  {long_name}");
+                                push_report(
+                                    &mut summary,
+                                    JobReport::failed(long_name.clone(), "interrupted".into()),
+                                );
                             }
+
+                            exit_code = INTERRUPTED_EXIT_CODE;
+                            break;
                         }
+                    };
+
+                    let (uuid, result) = match result {
+                        Ok(result) => result,
                         Err(err) => bail!(err),
+                    };
+                    let long_name = running_jobs.remove(&uuid).unwrap_or_default();
+
+                    match result {
+                        Ok((report, job)) => {
+                            push_report(&mut summary, report.clone());
+                            let JobReport {
+                                long_name,
+                                status,
+                                attempts,
+                                ..
+                            } = report;
+                            let attempts = match attempts {
+                                0 | 1 => String::new(),
+                                attempts => format!(" after {attempts} attempts"),
+                            };
+
+                            match job.job.on_fail {
+                                Some(OnFail::Ignore) if !status.success() => {
+                                    warn!("{long_name} {status}{attempts} but was ignored");
+                                    scheduler.finish(uuid);
+                                }
+                                Some(OnFail::Stop) | None if !status.success() => {
+                                    error!("Build failed for {long_name}{attempts}: {status}");
+                                    exit_code = 1;
+                                    stopping = !keep_going;
+                                    skip_dependents(
+                                        &mut scheduler,
+                                        &mut jobs,
+                                        &mut summary,
+                                        uuid,
+                                        &long_name,
+                                    );
+                                }
+                                _ => {
+                                    info!("{long_name} finished with status {status}{attempts}");
+                                    scheduler.finish(uuid);
+                                }
+                            }
+                        }
+                        Err(err) => {
+                            error!("{err}");
+                            push_report(
+                                &mut summary,
+                                JobReport::failed(long_name.clone(), format!("{err:#}")),
+                            );
+                            exit_code = 1;
+                            stopping = !keep_going;
+                            skip_dependents(
+                                &mut scheduler,
+                                &mut jobs,
+                                &mut summary,
+                                uuid,
+                                &long_name,
+                            );
+                        }
                     }
                 }
 
+                // The jobs that never started because the run stopped or was interrupted
+                let reason = match exit_code {
+                    INTERRUPTED_EXIT_CODE => "the run was interrupted",
+                    _ => "the run stopped after a job failed",
+                };
+                let mut not_started: Vec<_> = jobs.into_values().collect();
+                not_started.sort_by_key(|(job_index, job)| (*job_index, job.long_name(*job_index)));
+                for (job_index, job) in not_started {
+                    let long_name = job.long_name(job_index);
+                    warn!("Skipping {long_name} because {reason}");
+                    push_report(
+                        &mut summary,
+                        JobReport::skipped(long_name, &job.job, reason.into()),
+                    );
+                }
+
+                summary.print(pipeline_started.elapsed());
+
+                events::emit(Event::PipelineFinished {
+                    status: match exit_code {
+                        0 => "success",
+                        INTERRUPTED_EXIT_CODE => "interrupted",
+                        _ => "failed",
+                    },
+                    exit_code,
+                    duration_ms: pipeline_started.elapsed().as_millis() as u64,
+                });
+
+                if let Some(junit) = junit {
+                    junit::write(&junit, &pipeline_name, &summary.reports)?;
+                    info!("Wrote JUnit report to {}", junit.display().bold());
+                }
+
                 #[cfg(feature = "telemetry")]
                 if let Some(join) = telem_join {
                     join.await.ok();
@@ -781,6 +1401,91 @@ impl Commands {
                     std::process::exit(exit_code)
                 }
             }
+            Commands::Plan {
+                pipeline,
+                format,
+                job_filters,
+            } => {
+                let pipeline_path = resolve_pipeline(select_pipeline(pipeline)?)?;
+                let pipeline_name = pipeline_path
+                    .file_name()
+                    .unwrap()
+                    .to_str()
+                    .unwrap()
+                    .to_owned();
+
+                let deno_exe = deno_exe().await?;
+                let project_directory = project_directory(&pipeline_path);
+                let pipeline_url = Url::from_file_path(&pipeline_path)
+                    .map_err(|_| anyhow::anyhow!("Unable to convert pipeline path to URL"))?;
+
+                let pipeline =
+                    match serialize_pipeline(&deno_exe, &pipeline_url, &project_directory).await? {
+                        CicadaType::Pipeline(pipeline) => pipeline,
+                        CicadaType::Image(image) => Pipeline {
+                            jobs: vec![image.job],
+                            ..Default::default()
+                        },
+                    };
+
+                let skip_reason =
+                    skip_reason(pipeline.on, &deno_exe, &pipeline_url, &project_directory).await?;
+
+                let jobs = expand_jobs(filter_jobs(
+                    pipeline.jobs.into_iter().enumerate().collect(),
+                    &job_filters,
+                )?);
+
+                Plan::new(pipeline_name, skip_reason, jobs)?.print(format)?;
+            }
+            Commands::Validate {
+                pipeline,
+                secret_args,
+            } => {
+                let pipeline_path = resolve_pipeline(select_pipeline(pipeline)?)?;
+
+                let deno_exe = deno_exe().await?;
+                let project_directory = project_directory(&pipeline_path);
+                let pipeline_url = Url::from_file_path(&pipeline_path)
+                    .map_err(|_| anyhow::anyhow!("Unable to convert pipeline path to URL"))?;
+
+                let jobs =
+                    match serialize_pipeline(&deno_exe, &pipeline_url, &project_directory).await? {
+                        CicadaType::Pipeline(pipeline) => pipeline.jobs,
+                        CicadaType::Image(image) => vec![image.job],
+                    };
+
+                // Check the jobs the same way as a run, the graph before and the jobs after the
+                // matrix expansion
+                let jobs = jobs.into_iter().enumerate().collect::<Vec<_>>();
+                let mut problems = validate::validate_graph(&jobs);
+                problems.extend(validate::validate_jobs(
+                    &expand_jobs(jobs),
+                    &secret_names(&secret_args.load()?),
+                ));
+                check_pipeline(problems)?;
+
+                info!("Pipeline is valid: {}", pipeline_path.display().bold());
+            }
+            Commands::Graph { pipeline, format } => {
+                let pipeline_path = resolve_pipeline(select_pipeline(pipeline)?)?;
+
+                let deno_exe = deno_exe().await?;
+                let project_directory = project_directory(&pipeline_path);
+                let pipeline_url = Url::from_file_path(&pipeline_path)
+                    .map_err(|_| anyhow::anyhow!("Unable to convert pipeline path to URL"))?;
+
+                let jobs =
+                    match serialize_pipeline(&deno_exe, &pipeline_url, &project_directory).await? {
+                        CicadaType::Pipeline(pipeline) => pipeline.jobs,
+                        CicadaType::Image(image) => vec![image.job],
+                    };
+
+                print!(
+                    "{}",
+                    graph::render(&expand_jobs(jobs.into_iter().enumerate().collect()), format)
+                );
+            }
             Commands::Step { workflow, step } => {
                 run_deno(
                     &RUN_STEP_SCRIPT,
@@ -956,6 +1661,10 @@ impl Commands {
             Commands::Update => {
                 anyhow::bail!("self update is not enabled in this build");
             }
+            Commands::Schema => {
+                let schema = schemars::schema_for!(CicadaType);
+                println!("{}", serde_json::to_string_pretty(&schema)?);
+            }
             Commands::Completions { shell } => {
                 use clap::CommandFactory;
                 generate(
@@ -997,6 +1706,10 @@ impl Commands {
     pub fn subcommand(&self) -> &'static str {
         match self {
             Commands::Run { .. } => "run",
+            Commands::Plan { .. } => "plan",
+            Commands::Graph { .. } => "graph",
+            Commands::Validate { .. } => "validate",
+            Commands::Schema => "schema",
             Commands::Step { .. } => "step",
             Commands::Init { .. } => "init",
             Commands::New { .. } => "new",
@@ -1014,6 +1727,10 @@ impl Commands {
     fn track(&self) -> bool {
         match self {
             Commands::Run { .. } => true,
+            Commands::Plan { .. } => true,
+            Commands::Graph { .. } => true,
+            Commands::Validate { .. } => true,
+            Commands::Schema => false,
             Commands::Step { .. } => false,
             Commands::Init { .. } => true,
             Commands::New { .. } => true,
@@ -0,0 +1,196 @@
+use anyhow::{Context, Result};
+use regex::Regex;
+
+/// A filter pattern, using the same syntax as github actions:
+///
+/// - `*` matches any characters except `/`
+/// - `**` matches any characters
+/// - `?` matches zero or one of the preceding character
+/// - `+` matches one or more of the preceding character
+/// - `[]` matches one character in the brackets, ranges like `[0-9]` are supported
+/// - `!` at the start of a pattern excludes what it matches
+/// - `\` escapes the next character
+#[derive(Debug, Clone)]
+pub struct Pattern {
+    regex: Regex,
+    exclude: bool,
+}
+
+impl Pattern {
+    pub fn new(pattern: &str) -> Result<Self> {
+        let (exclude, pattern) = match pattern.strip_prefix('!') {
+            Some(pattern) => (true, pattern),
+            None => (false, pattern),
+        };
+
+        let regex = Regex::new(&to_regex(pattern))
+            .with_context(|| format!("Invalid pattern: {pattern}"))?;
+
+        Ok(Self { regex, exclude })
+    }
+
+    pub fn is_match(&self, value: &str) -> bool {
+        self.regex.is_match(value)
+    }
+}
+
+/// Check if a value matches a list of patterns. Patterns are checked in order so a later pattern
+/// overrides an earlier one, if there are only exclude patterns everything else is included. An
+/// empty list matches nothing.
+pub fn matches(patterns: &[Pattern], value: &str) -> bool {
+    let mut matched = !patterns.is_empty() && patterns.iter().all(|pattern| pattern.exclude);
+
+    for pattern in patterns {
+        if pattern.is_match(value) {
+            matched = !pattern.exclude;
+        }
+    }
+
+    matched
+}
+
+/// Parse a list of patterns
+pub fn parse(patterns: &[String]) -> Result<Vec<Pattern>> {
+    patterns
+        .iter()
+        .map(|pattern| Pattern::new(pattern))
+        .collect()
+}
+
+fn to_regex(pattern: &str) -> String {
+    let mut regex = String::from("^");
+    let mut chars = pattern.chars().peekable();
+    // Quantifiers need something to apply to, otherwise they are literal
+    let mut can_repeat = false;
+
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                regex.push_str(".*");
+                can_repeat = false;
+            }
+            '*' => {
+                regex.push_str("[^/]*");
+                can_repeat = false;
+            }
+            '?' | '+' if can_repeat => {
+                regex.push(c);
+                can_repeat = false;
+            }
+            '[' => {
+                let mut class = String::new();
+                let mut closed = false;
+                for c in chars.by_ref() {
+                    match c {
+                        ']' => {
+                            closed = true;
+                            break;
+                        }
+                        '\\' | '[' | '&' | '~' => {
+                            class.push('\\');
+                            class.push(c);
+                        }
+                        _ => class.push(c),
+                    }
+                }
+
+                if closed && !class.is_empty() {
+                    regex.push('[');
+                    regex.push_str(&class);
+                    regex.push(']');
+                } else {
+                    // Not a character class, match it literally
+                    regex.push_str(&regex::escape(&format!("[{class}")));
+                    if closed {
+                        regex.push_str(&regex::escape("]"));
+                    }
+                }
+                can_repeat = true;
+            }
+            '\\' => {
+                if let Some(c) = chars.next() {
+                    regex.push_str(&regex::escape(&c.to_string()));
+                }
+                can_repeat = true;
+            }
+            _ => {
+                regex.push_str(&regex::escape(&c.to_string()));
+                can_repeat = true;
+            }
+        }
+    }
+
+    regex.push('$');
+    regex
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(patterns: &[&str], value: &str) -> bool {
+        let patterns = patterns.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+        matches(&parse(&patterns).unwrap(), value)
+    }
+
+    #[test]
+    fn exact() {
+        assert!(check(&["main"], "main"));
+        assert!(!check(&["main"], "main2"));
+        assert!(!check(&["main"], "feature/main"));
+    }
+
+    #[test]
+    fn star() {
+        assert!(check(&["release/*"], "release/1.0"));
+        assert!(!check(&["release/*"], "release/1.0/hotfix"));
+        assert!(!check(&["release/*"], "release"));
+        assert!(check(&["*"], "main"));
+        assert!(!check(&["*"], "feature/main"));
+    }
+
+    #[test]
+    fn double_star() {
+        assert!(check(&["feature/**"], "feature/a/b"));
+        assert!(check(&["**"], "feature/a/b"));
+        assert!(check(&["**/docs/**"], "services/api/docs/readme.md"));
+    }
+
+    #[test]
+    fn quantifiers() {
+        assert!(check(&["v[0-9]+.*"], "v12.0"));
+        assert!(check(&["v[0-9]+.*"], "v1.x"));
+        assert!(!check(&["v[0-9]+.*"], "v.1"));
+        assert!(!check(&["v[0-9]+.*"], "v1-0"));
+        assert!(check(&["colou?r"], "color"));
+        assert!(check(&["colou?r"], "colour"));
+        assert!(check(&["?main"], "?main"));
+    }
+
+    #[test]
+    fn escapes() {
+        assert!(check(&["a\\*b"], "a*b"));
+        assert!(!check(&["a\\*b"], "axb"));
+        assert!(check(&["a(b)"], "a(b)"));
+        assert!(check(&["[abc"], "[abc"));
+    }
+
+    #[test]
+    fn exclude() {
+        assert!(!check(&["!dependabot/**"], "dependabot/npm/foo"));
+        assert!(check(&["!dependabot/**"], "main"));
+        assert!(check(&["**", "!dependabot/**"], "main"));
+        assert!(!check(&["**", "!dependabot/**"], "dependabot/npm/foo"));
+        assert!(check(
+            &["**", "!dependabot/**", "dependabot/keep"],
+            "dependabot/keep"
+        ));
+        assert!(!check(&["main", "!main"], "main"));
+    }
+
+    #[test]
+    fn empty() {
+        assert!(!check(&[], "main"));
+    }
+}
@@ -0,0 +1,247 @@
+use ahash::HashMap;
+use anyhow::Result;
+use clap::ValueEnum;
+use owo_colors::{OwoColorize, Stream};
+use serde::Serialize;
+
+use crate::{
+    dag::{invert_graph, topological_sort, Node},
+    job::{CacheDirectory, Job},
+};
+
+#[derive(Debug, Clone, Copy, ValueEnum, PartialEq, Eq, Default)]
+pub enum PlanFormat {
+    #[default]
+    Human,
+    Json,
+}
+
+/// What running a pipeline would do, built without touching the OCI backend
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Plan {
+    pub pipeline: String,
+    pub trigger: PlanTrigger,
+    /// Jobs grouped by the order they can run in, every job in a stage only depends on jobs in
+    /// earlier stages
+    pub stages: Vec<Vec<PlanJob>>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PlanTrigger {
+    pub run: bool,
+    pub skip_reason: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PlanJob {
+    pub name: String,
+    pub image: String,
+    pub platform: Option<String>,
+    pub depends_on: Vec<String>,
+    pub cache_directories: Vec<CacheDirectory>,
+    pub steps: Vec<PlanStep>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PlanStep {
+    pub name: String,
+    pub secrets: Vec<String>,
+    pub cache_directories: Vec<CacheDirectory>,
+}
+
+impl Plan {
+    /// Order the expanded jobs into stages
+    pub fn new(
+        pipeline: String,
+        skip_reason: Option<String>,
+        jobs: Vec<(usize, Job)>,
+    ) -> Result<Self> {
+        let nodes: Vec<Node> = jobs
+            .iter()
+            .map(|(_, job)| Node::new(job.uuid, job.depends_on.clone()))
+            .collect();
+        let mut execution_order = topological_sort(&invert_graph(&nodes))?;
+
+        // Keep the order of the pipeline within a stage so the plan is the same every time
+        let positions: HashMap<_, _> = jobs
+            .iter()
+            .enumerate()
+            .map(|(position, (_, job))| (job.uuid, position))
+            .collect();
+        for stage in &mut execution_order {
+            stage.sort_by_key(|uuid| positions[uuid]);
+        }
+
+        let names: HashMap<_, _> = jobs
+            .iter()
+            .map(|(index, job)| (job.uuid, job.long_name(*index)))
+            .collect();
+        let mut jobs: HashMap<_, _> = jobs
+            .into_iter()
+            .map(|(index, job)| (job.uuid, (index, job)))
+            .collect();
+
+        let stages = execution_order
+            .into_iter()
+            .map(|stage| {
+                stage
+                    .into_iter()
+                    .filter_map(|uuid| jobs.remove(&uuid))
+                    .map(|(index, job)| PlanJob {
+                        name: job.long_name(index),
+                        depends_on: job
+                            .depends_on
+                            .iter()
+                            // Dependencies that were filtered out are not run
+                            .filter_map(|uuid| names.get(uuid).cloned())
+                            .collect(),
+                        steps: job
+                            .steps
+                            .iter()
+                            .enumerate()
+                            .map(|(step_index, step)| PlanStep {
+                                name: step.display_name(step_index),
+                                secrets: step.secrets.clone(),
+                                cache_directories: step.cache_directories.clone(),
+                            })
+                            .collect(),
+                        image: job.image,
+                        platform: job.platform,
+                        cache_directories: job.cache_directories,
+                    })
+                    .collect()
+            })
+            .collect();
+
+        Ok(Self {
+            pipeline,
+            trigger: PlanTrigger {
+                run: skip_reason.is_none(),
+                skip_reason,
+            },
+            stages,
+        })
+    }
+
+    pub fn print(&self, format: PlanFormat) -> Result<()> {
+        match format {
+            PlanFormat::Human => self.print_human(),
+            PlanFormat::Json => println!("{}", serde_json::to_string_pretty(self)?),
+        }
+
+        Ok(())
+    }
+
+    fn print_human(&self) {
+        println!(
+            "Pipeline: {}",
+            self.pipeline
+                .if_supports_color(Stream::Stdout, |s| s.bold())
+        );
+        match &self.trigger.skip_reason {
+            Some(reason) => println!("Trigger: skipped because {reason}"),
+            None => println!("Trigger: runs"),
+        }
+
+        for (stage_index, stage) in self.stages.iter().enumerate() {
+            println!("\nStage {}", stage_index + 1);
+
+            for job in stage {
+                println!(
+                    "  {}",
+                    job.name.if_supports_color(Stream::Stdout, |s| s.bold())
+                );
+                println!("    image: {}", job.image);
+                if let Some(platform) = &job.platform {
+                    println!("    platform: {platform}");
+                }
+                if !job.depends_on.is_empty() {
+                    println!("    depends on: {}", job.depends_on.join(", "));
+                }
+                if !job.cache_directories.is_empty() {
+                    println!("    cache: {}", cache_paths(&job.cache_directories));
+                }
+
+                println!("    steps:");
+                for step in &job.steps {
+                    println!("      - {}", step.name);
+                    if !step.secrets.is_empty() {
+                        println!("        secrets: {}", step.secrets.join(", "));
+                    }
+                    if !step.cache_directories.is_empty() {
+                        println!("        cache: {}", cache_paths(&step.cache_directories));
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn cache_paths(cache_directories: &[CacheDirectory]) -> String {
+    cache_directories
+        .iter()
+        .map(|cache_directory| cache_directory.path.as_str())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::job::{test_jobs, test_uuid};
+
+    #[test]
+    fn stages() {
+        // deploy depends on test and lint, test on install, docs on lint
+        let jobs = test_jobs(serde_json::json!([
+            {
+                "name": "test",
+                "dependsOn": [test_uuid(1)],
+                "steps": [{ "run": { "type": "command", "command": "npm test" } }],
+            },
+            {
+                "name": "install",
+                "steps": [{
+                    "run": { "type": "command", "command": "npm ci" },
+                    "secrets": ["NPM_TOKEN"],
+                    "cacheDirectories": [{ "path": "node_modules" }],
+                }],
+            },
+            { "name": "lint" },
+            { "name": "deploy", "dependsOn": [test_uuid(0), test_uuid(2)] },
+            { "name": "docs", "dependsOn": [test_uuid(2)] },
+        ]));
+
+        // The graph is walked through hash maps, the stages should not depend on their order
+        for _ in 0..20 {
+            let plan = Plan::new(
+                "ci.ts".into(),
+                None,
+                jobs.clone().into_iter().enumerate().collect(),
+            )
+            .unwrap();
+
+            let stages: Vec<Vec<&str>> = plan
+                .stages
+                .iter()
+                .map(|stage| stage.iter().map(|job| job.name.as_str()).collect())
+                .collect();
+            assert_eq!(
+                stages,
+                vec![
+                    vec!["install (node-1)", "lint (node-2)"],
+                    vec!["test (node-0)", "docs (node-4)"],
+                    vec!["deploy (node-3)"],
+                ]
+            );
+
+            assert!(plan.trigger.run);
+            assert_eq!(plan.stages[0][0].steps[0].secrets, vec!["NPM_TOKEN"]);
+            assert_eq!(plan.stages[1][0].depends_on, vec!["install (node-1)"]);
+        }
+    }
+}
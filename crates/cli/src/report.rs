@@ -0,0 +1,267 @@
+use std::time::Duration;
+
+use owo_colors::{OwoColorize, Stream};
+use tracing::info;
+
+use crate::job::{JobReport, JobStatus, StepStatus};
+
+/// The reports of the jobs in a run, in the order they finished
+#[derive(Debug, Clone, Default)]
+pub struct RunSummary {
+    pub reports: Vec<JobReport>,
+}
+
+/// A row of the summary table
+struct Row {
+    job: String,
+    status: &'static str,
+    time: String,
+    attempts: String,
+    steps: String,
+    cached: String,
+    executed: String,
+    exit: String,
+}
+
+impl Row {
+    fn new(report: &JobReport) -> Self {
+        let status = match (&report.status, report.ignored) {
+            (JobStatus::Success, _) => "passed",
+            (JobStatus::Skipped { .. }, _) => "skipped",
+            (JobStatus::TimedOut { .. }, false) => "timed out",
+            (_, true) => "ignored",
+            (JobStatus::Failed { .. }, false) => "failed",
+        };
+
+        let ran = report
+            .steps
+            .iter()
+            .filter(|step| step.status != StepStatus::Skipped);
+        let cached = ran.clone().filter(|step| step.cached).count();
+        let executed = ran.filter(|step| !step.cached).count();
+
+        Self {
+            job: report.long_name.clone(),
+            status,
+            time: match report.status {
+                JobStatus::Skipped { .. } => "-".into(),
+                _ => format_duration(report.duration),
+            },
+            attempts: match report.status {
+                JobStatus::Skipped { .. } => "-".into(),
+                _ => report.attempts.to_string(),
+            },
+            steps: report.steps.len().to_string(),
+            cached: cached.to_string(),
+            executed: executed.to_string(),
+            exit: exit_code(report).map_or_else(|| "-".into(), |code| code.to_string()),
+        }
+    }
+
+    fn cells(&self) -> [&str; 8] {
+        [
+            &self.job,
+            self.status,
+            &self.time,
+            &self.attempts,
+            &self.steps,
+            &self.cached,
+            &self.executed,
+            &self.exit,
+        ]
+    }
+}
+
+const HEADER: [&str; 8] = [
+    "JOB", "STATUS", "TIME", "ATTEMPTS", "STEPS", "CACHED", "EXECUTED", "EXIT",
+];
+
+impl RunSummary {
+    /// Print a table of how each job went
+    pub fn print(&self, duration: Duration) {
+        info!("\n{} in {}\n", self.totals(), format_duration(duration));
+
+        for line in self.table() {
+            info!("{line}");
+        }
+    }
+
+    /// How many jobs passed, failed, were ignored after failing and were skipped, only failed jobs
+    /// fail the run
+    fn totals(&self) -> String {
+        let rows = self.rows();
+        let count = |statuses: &[&str]| {
+            rows.iter()
+                .filter(|row| statuses.contains(&row.status))
+                .count()
+        };
+
+        format!(
+            "{} passed, {} failed, {} ignored, {} skipped",
+            count(&["passed"]),
+            count(&["failed", "timed out"]),
+            count(&["ignored"]),
+            count(&["skipped"])
+        )
+    }
+
+    fn rows(&self) -> Vec<Row> {
+        self.reports.iter().map(Row::new).collect()
+    }
+
+    fn table(&self) -> Vec<String> {
+        let rows = self.rows();
+
+        let mut widths = HEADER.map(str::len);
+        for row in &rows {
+            for (width, cell) in widths.iter_mut().zip(row.cells()) {
+                *width = (*width).max(cell.chars().count());
+            }
+        }
+
+        let line = |cells: [&str; 8], status: &str| {
+            cells
+                .iter()
+                .zip(widths)
+                .enumerate()
+                .map(|(column, (cell, width))| {
+                    // Pad before coloring so the color codes do not count towards the width
+                    let cell = match column {
+                        0 | 1 => format!("{cell:<width$}"),
+                        _ => format!("{cell:>width$}"),
+                    };
+                    match (column, status) {
+                        (1, "passed") => cell
+                            .if_supports_color(Stream::Stderr, |s| s.green())
+                            .to_string(),
+                        (1, "failed" | "timed out") => cell
+                            .if_supports_color(Stream::Stderr, |s| s.red())
+                            .to_string(),
+                        (1, "skipped" | "ignored") => cell
+                            .if_supports_color(Stream::Stderr, |s| s.yellow())
+                            .to_string(),
+                        _ => cell,
+                    }
+                })
+                .collect::<Vec<_>>()
+                .join("  ")
+                .trim_end()
+                .to_owned()
+        };
+
+        std::iter::once(line(HEADER, ""))
+            .chain(rows.iter().map(|row| line(row.cells(), row.status)))
+            .collect()
+    }
+}
+
+/// The exit code of a job, `None` if it did not run a process that exited
+fn exit_code(report: &JobReport) -> Option<i32> {
+    match &report.status {
+        JobStatus::Success => Some(0),
+        JobStatus::Failed { exit_code, .. } => *exit_code,
+        JobStatus::TimedOut { .. } | JobStatus::Skipped { .. } => None,
+    }
+}
+
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs < 60 {
+        format!("{:.1}s", duration.as_secs_f64())
+    } else {
+        format!("{}m {:02}s", secs / 60, secs % 60)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::job::{test_jobs, StepReport};
+
+    fn step(status: StepStatus, cached: bool) -> StepReport {
+        StepReport {
+            name: "step".into(),
+            status,
+            cached,
+            duration: Duration::from_secs(1),
+            output: String::new(),
+        }
+    }
+
+    #[test]
+    fn table() {
+        let summary = RunSummary {
+            reports: vec![
+                JobReport {
+                    long_name: "install (node-0)".into(),
+                    status: JobStatus::Success,
+                    attempts: 1,
+                    duration: Duration::from_millis(2500),
+                    steps: vec![
+                        step(StepStatus::Success, true),
+                        step(StepStatus::Success, false),
+                    ],
+                    ignored: false,
+                },
+                JobReport {
+                    long_name: "test (node-1)".into(),
+                    status: JobStatus::Failed {
+                        error: "process did not complete successfully".into(),
+                        vertex: Some("step".into()),
+                        exit_code: Some(127),
+                    },
+                    attempts: 2,
+                    duration: Duration::from_secs(75),
+                    steps: vec![
+                        step(StepStatus::Success, true),
+                        step(
+                            StepStatus::Failed {
+                                error: r#"process "/bin/sh -c npm test" did not complete successfully: exit code: 127"#.into(),
+                            },
+                            false,
+                        ),
+                        step(StepStatus::Skipped, false),
+                    ],
+                    ignored: false,
+                },
+                JobReport {
+                    long_name: "lint (node-2)".into(),
+                    status: JobStatus::Failed {
+                        error: "process did not complete successfully".into(),
+                        vertex: Some("step".into()),
+                        exit_code: Some(1),
+                    },
+                    attempts: 1,
+                    duration: Duration::from_secs(4),
+                    steps: vec![step(
+                        StepStatus::Failed {
+                            error: "exit code: 1".into(),
+                        },
+                        false,
+                    )],
+                    ignored: true,
+                },
+                JobReport::skipped(
+                    "deploy".into(),
+                    &test_jobs(serde_json::json!([{
+                        "steps": [{ "run": { "type": "command", "command": "deploy" } }],
+                    }]))[0],
+                    "test (node-1) failed".into(),
+                ),
+            ],
+        };
+
+        owo_colors::set_override(false);
+        assert_eq!(
+            summary.table(),
+            vec![
+                "JOB               STATUS     TIME  ATTEMPTS  STEPS  CACHED  EXECUTED  EXIT",
+                "install (node-0)  passed     2.5s         1      2       1         1     0",
+                "test (node-1)     failed   1m 15s         2      3       1         1   127",
+                "lint (node-2)     ignored    4.0s         1      1       0         1     1",
+                "deploy            skipped       -         -      1       0         0     -",
+            ]
+        );
+        assert_eq!(summary.totals(), "1 passed, 1 failed, 1 ignored, 1 skipped");
+    }
+}
@@ -0,0 +1,323 @@
+use std::fmt;
+
+use ahash::{HashMap, HashSet};
+use buildkit_rs::{llb::Platform, reference::Reference};
+use camino::{Utf8Component, Utf8Path};
+use owo_colors::OwoColorize;
+
+use crate::job::Job;
+
+/// A problem in a pipeline that would make it fail to run
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Problem {
+    pub job: String,
+    pub step: Option<String>,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "job {}", self.job.bold())?;
+        if let Some(step) = &self.step {
+            write!(f, ", step {}", step.bold())?;
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+fn problem(index: usize, job: &Job, step: Option<String>, message: String) -> Problem {
+    Problem {
+        job: job.long_name(index),
+        step,
+        message,
+    }
+}
+
+/// Find the problems in how the jobs of a whole pipeline fit together: duplicate names, unknown
+/// dependencies and dependency cycles
+pub fn validate_graph(jobs: &[(usize, Job)]) -> Vec<Problem> {
+    let mut problems = vec![];
+
+    let uuids: HashSet<_> = jobs.iter().map(|(_, job)| job.uuid).collect();
+    let mut names: HashMap<&str, (usize, &Job)> = HashMap::default();
+
+    for (index, job) in jobs {
+        if let Some(name) = &job.name {
+            if let Some((first_index, first)) = names.get(name.as_str()) {
+                problems.push(problem(
+                    *index,
+                    job,
+                    None,
+                    format!(
+                        "the name {} is also used by {}",
+                        name.bold(),
+                        first.long_name(*first_index).bold()
+                    ),
+                ));
+            } else {
+                names.insert(name, (*index, job));
+            }
+        }
+
+        for uuid in &job.depends_on {
+            if !uuids.contains(uuid) {
+                problems.push(problem(
+                    *index,
+                    job,
+                    None,
+                    format!("depends on a job that is not in this pipeline: {uuid}"),
+                ));
+            }
+        }
+    }
+
+    // Jobs that never become ready are in or behind a dependency cycle
+    let mut finished = HashSet::default();
+    let mut pending: Vec<&(usize, Job)> = jobs.iter().collect();
+    loop {
+        let before = pending.len();
+        pending.retain(|(_, job)| {
+            let ready = job
+                .depends_on
+                .iter()
+                .all(|uuid| finished.contains(uuid) || !uuids.contains(uuid));
+            if ready {
+                finished.insert(job.uuid);
+            }
+            !ready
+        });
+
+        if pending.len() == before {
+            break;
+        }
+    }
+    for (index, job) in pending {
+        problems.push(problem(
+            *index,
+            job,
+            None,
+            "is part of or depends on a dependency cycle".into(),
+        ));
+    }
+
+    problems
+}
+
+/// Artifacts are copied to the same path in the artifacts directory, they can not leave it or be
+/// the whole directory
+fn artifact_problem(artifact: &Utf8Path) -> Option<String> {
+    if artifact.as_str().trim_start_matches('/').is_empty() {
+        Some(format!("empty artifact path {}", artifact.as_str().bold()))
+    } else if artifact
+        .components()
+        .any(|component| component == Utf8Component::ParentDir)
+    {
+        Some(format!(
+            "artifact {} can not contain {}",
+            artifact.bold(),
+            "..".bold()
+        ))
+    } else {
+        None
+    }
+}
+
+/// Find the problems that would fail the jobs themselves: invalid images or platforms, secrets
+/// the run does not have and dependencies whose artifacts would overwrite each other
+pub fn validate_jobs(jobs: &[(usize, Job)], secrets: &HashSet<String>) -> Vec<Problem> {
+    let mut problems = vec![];
+
+    let jobs_by_uuid: HashMap<uuid::Uuid, (usize, &Job)> = jobs
+        .iter()
+        .map(|(index, job)| (job.uuid, (*index, job)))
+        .collect();
+
+    for (index, job) in jobs {
+        if let Err(err) = Reference::parse_normalized_named(&job.image) {
+            problems.push(problem(
+                *index,
+                job,
+                None,
+                format!("invalid image {}: {err}", job.image.bold()),
+            ));
+        }
+
+        if let Some(platform) = &job.platform {
+            if let Err(err) = platform.parse::<Platform>() {
+                problems.push(problem(
+                    *index,
+                    job,
+                    None,
+                    format!("invalid platform {}: {err}", platform.bold()),
+                ));
+            }
+        }
+
+        for artifact in &job.artifacts {
+            if let Some(message) = artifact_problem(artifact) {
+                problems.push(problem(*index, job, None, message));
+            }
+        }
+
+        for (step_index, step) in job.steps.iter().enumerate() {
+            for artifact in &step.artifacts {
+                if let Some(message) = artifact_problem(artifact) {
+                    problems.push(problem(
+                        *index,
+                        job,
+                        Some(step.display_name(step_index)),
+                        message,
+                    ));
+                }
+            }
+
+            for secret in &step.secrets {
+                if !secrets.contains(secret) {
+                    problems.push(problem(
+                        *index,
+                        job,
+                        Some(step.display_name(step_index)),
+                        format!("unknown secret {}", secret.bold()),
+                    ));
+                }
+            }
+        }
+
+        // The artifacts of every dependency are copied into the same working directory, the
+        // expansions of a matrix job export the same paths
+        let mut artifact_sources: HashMap<&str, String> = HashMap::default();
+        for (dependency_index, dependency) in job
+            .depends_on
+            .iter()
+            .filter_map(|uuid| jobs_by_uuid.get(uuid))
+        {
+            for artifact in dependency.artifact_paths() {
+                let path = artifact.as_str().trim_start_matches('/');
+                let name = dependency.long_name(*dependency_index);
+                match artifact_sources.get(path) {
+                    Some(other) if *other != name => problems.push(problem(
+                        *index,
+                        job,
+                        None,
+                        format!(
+                            "the artifact {} of {} and {} would overwrite each other",
+                            artifact.bold(),
+                            other.bold(),
+                            name.bold()
+                        ),
+                    )),
+                    Some(_) => {}
+                    None => {
+                        artifact_sources.insert(path, name);
+                    }
+                }
+            }
+        }
+    }
+
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::job::{test_jobs, test_uuid};
+
+    #[test]
+    fn reports_every_problem() {
+        let jobs = test_jobs(serde_json::json!([
+            {
+                "name": "build",
+                "dependsOn": [test_uuid(9)],
+                "steps": [{
+                    "run": { "type": "command", "command": "npm publish" },
+                    "secrets": ["NPM_TOKEN", "GITHUB_TOKEN"],
+                }],
+            },
+            { "image": "Not An Image", "name": "build" },
+            { "dependsOn": [test_uuid(3)] },
+            { "dependsOn": [test_uuid(2)] },
+        ]))
+        .into_iter()
+        .enumerate()
+        .collect::<Vec<_>>();
+        let secrets = HashSet::from_iter(["GITHUB_TOKEN".to_owned()]);
+
+        let mut problems = validate_graph(&jobs);
+        problems.extend(validate_jobs(&jobs, &secrets));
+        let found: Vec<_> = problems
+            .iter()
+            .map(|problem| (problem.job.as_str(), problem.step.as_deref()))
+            .collect();
+
+        assert_eq!(
+            found,
+            vec![
+                ("build (node-0)", None),
+                ("build (Not An Image-1)", None),
+                ("node-2", None),
+                ("node-3", None),
+                ("build (node-0)", Some("Step 0: npm publish")),
+                ("build (Not An Image-1)", None),
+            ]
+        );
+        assert!(problems[2].message.contains("cycle"));
+    }
+
+    #[test]
+    fn dependency_artifacts_overwrite_each_other() {
+        let jobs = test_jobs(serde_json::json!([
+            { "name": "build (linux/amd64)", "artifacts": ["dist"] },
+            { "name": "build (linux/arm64)", "artifacts": ["/dist"] },
+            { "name": "docs", "artifacts": ["site"] },
+            { "name": "release", "dependsOn": [test_uuid(0), test_uuid(1), test_uuid(2)] },
+        ]))
+        .into_iter()
+        .enumerate()
+        .collect::<Vec<_>>();
+
+        let problems = validate_jobs(&jobs, &HashSet::default());
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].job, "release (node-3)");
+        assert!(problems[0].message.contains("overwrite"));
+    }
+
+    #[test]
+    fn artifacts_stay_in_the_artifacts_directory() {
+        let jobs = test_jobs(serde_json::json!([{
+            "artifacts": ["", "dist/../../secrets", "./dist"],
+            "steps": [{
+                "run": { "type": "command", "command": "npm test" },
+                "artifacts": ["/", "../coverage"],
+            }],
+        }]))
+        .into_iter()
+        .enumerate()
+        .collect::<Vec<_>>();
+
+        let found: Vec<_> = validate_jobs(&jobs, &HashSet::default())
+            .into_iter()
+            .map(|problem| problem.step)
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                None,
+                None,
+                Some("Step 0: npm test".into()),
+                Some("Step 0: npm test".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn valid() {
+        let jobs = test_jobs(serde_json::json!([{ "image": "docker.io/library/node:18" }]))
+            .into_iter()
+            .enumerate()
+            .collect::<Vec<_>>();
+
+        assert!(validate_graph(&jobs).is_empty());
+        assert!(validate_jobs(&jobs, &HashSet::default()).is_empty());
+    }
+}
@@ -175,6 +175,38 @@ export interface StepOptions {
    * @default "sh"
    */
   shell?: Shell;
+
+  /**
+   * Files or directories to export from the job after this step has run. Relative paths are relative to the working directory of the step.
+   *
+   * Artifacts are written to `.cicada/artifacts/<run>/<job>` once the job succeeds.
+   *
+   * @example
+   * ```ts
+   * const step: StepOptions = {
+   *  run: "npm test -- --coverage",
+   *  artifacts: ["coverage"]
+   * }
+   * ```
+   */
+  artifacts?: FilePath[];
+
+  /**
+   * The number of seconds the step may run for. If the step runs for longer the job is cancelled and reported as timed out.
+   */
+  timeout?: number;
+
+  /**
+   * The number of times to retry the job when this step fails, this overrides the retries of the job. Steps that already succeeded are cached so only this step runs again.
+   */
+  retries?: number;
+
+  /**
+   * The number of seconds to wait before the first retry, the wait is doubled for every retry after.
+   *
+   * @default 0
+   */
+  retryBackoff?: number;
 }
 
 /**
@@ -244,6 +276,73 @@ export interface JobOptions extends CommonImageOptions {
    * @default "stop"
    */
   onFail?: "ignore" | "stop";
+
+  /**
+   * Files or directories to export from the job once all steps have run. Relative paths are relative to the working directory of the job.
+   *
+   * Artifacts are written to `.cicada/artifacts/<run>/<job>` once the job succeeds. Jobs that depend on this job get the artifacts copied into their working directory at the path they were declared with, absolute paths are copied relative to the working directory as well. Two dependencies, such as the expansions of a matrix job, can not export the same path.
+   *
+   * @example
+   * `["target/release/app"]` will export the release binary
+   */
+  artifacts?: FilePath[];
+
+  /**
+   * Run the job once for every combination of the values in the matrix. Each combination runs as a separate job named after its values, and jobs that depend on this job wait for all of them.
+   *
+   * @example
+   * `{ image: ["node:18", "node:20"], platform: ["linux/amd64", "linux/arm64"] }` will run the job four times
+   */
+  matrix?: Matrix;
+
+  /**
+   * The platform to run the job on, defaults to the `--platform` passed to `cicada run`. When a list of platforms is given the job runs once for each platform, the same as adding them to the {@link Matrix.platform matrix}.
+   *
+   * @example "linux/arm64", ["linux/amd64", "linux/arm64"]
+   */
+  platform?: string | string[];
+
+  /**
+   * The number of seconds the job may run for. If the job runs for longer it is cancelled and reported as timed out, `onFail` decides if the pipeline stops.
+   */
+  timeout?: number;
+
+  /**
+   * The number of times to retry the job when it fails. Steps that already succeeded are cached so only the failed step runs again.
+   */
+  retries?: number;
+
+  /**
+   * The number of seconds to wait before the first retry, the wait is doubled for every retry after.
+   *
+   * @default 0
+   */
+  retryBackoff?: number;
+}
+
+/**
+ * The values to run a job with, see {@link JobOptions.matrix}
+ */
+export interface Matrix {
+  /**
+   * Base images to run the job with, replaces the image of the job
+   */
+  image?: DockerImages[];
+
+  /**
+   * Platforms to run the job on
+   *
+   * @example "linux/amd64", "linux/arm64"
+   */
+  platform?: string[];
+
+  /**
+   * Values for environment variables, each variable is set to every one of its values
+   *
+   * @example
+   * `{ NODE_ENV: ["development", "production"] }`
+   */
+  env?: Record<string, string[]>;
 }
 
 /**
@@ -263,7 +362,18 @@ export class Job {
 }
 
 /**
- * A git branch represented as a string.
+ * A git branch represented as a string, or a pattern matching branches.
+ *
+ * - `*` matches any characters except `/`
+ * - `**` matches any characters
+ * - `?` matches zero or one of the preceding character
+ * - `+` matches one or more of the preceding character
+ * - `[]` matches one character in the brackets, ranges like `[0-9]` are supported
+ * - `!` at the start excludes the branches it matches
+ *
+ * Patterns are checked in order so a later pattern overrides an earlier one.
+ *
+ * @example "main", "release/*", "!dependabot/**", "v[0-9]+.*"
  */
 export type Branch = string;
 
@@ -284,17 +394,86 @@ export interface TriggerOptions {
    * Use `'all'` if you'd like for any branch to trigger the pipeline.
    */
   pullRequest?: Branch[] | "all";
+
+  /**
+   * Which tags when pushed should trigger the pipeline. Tags use the same patterns as {@link Branch branches}.
+   *
+   * Use `'all'` if you'd like for any tag to trigger the pipeline.
+   *
+   * @example
+   * `["v[0-9]+.*"]` will trigger the pipeline for version tags
+   */
+  tag?: string[] | "all";
+
+  /**
+   * Which branches should trigger the pipeline when it runs on a schedule.
+   *
+   * Use `'all'` if you'd like for any branch to trigger the pipeline.
+   */
+  schedule?: Branch[] | "all";
+
+  /**
+   * Which branches should trigger the pipeline when it is run manually, like with `workflow_dispatch` on GitHub Actions.
+   *
+   * Use `'all'` if you'd like for any branch to trigger the pipeline.
+   */
+  manual?: Branch[] | "all";
+
+  /**
+   * Only run the pipeline if a file changed between the base branch and `HEAD` matches one of these patterns. Patterns use the same syntax as {@link Branch branches}.
+   *
+   * @example
+   * `["services/api/**"]` will only run the pipeline when the api changes
+   */
+  paths?: string[];
+
+  /**
+   * Do not run the pipeline if every changed file matches one of these patterns. Patterns use the same syntax as {@link Branch branches}.
+   *
+   * @example
+   * `["docs/**", "**.md"]` will not run the pipeline when only documentation changes
+   */
+  pathsIgnore?: string[];
+}
+
+/**
+ * The context passed to a {@link TriggerFn trigger function}.
+ */
+export interface TriggerContext {
+  /**
+   * The git event that triggered the pipeline.
+   *
+   * @example "push", "pull_request"
+   */
+  event: string;
+
+  /**
+   * The branch that was pushed to or that the pull request targets.
+   */
+  baseRef: string;
+
+  /**
+   * The files changed between the base ref and `HEAD`, relative to the root of the repository.
+   */
+  changedFiles: string[];
+
+  /**
+   * The environment variables of the cicada process.
+   */
+  env: Record<string, string>;
 }
 
 /**
  * A trigger function that returns a boolean value indicating whether the pipeline should run.
  */
-// export type TriggerFn = () => boolean | Promise<boolean>;
+export type TriggerFn = (
+  context: TriggerContext,
+) => boolean | Promise<boolean>;
 
 /**
  * The trigger events which determines when a pipeline should run.
  */
-export type Trigger = TriggerOptions; //TriggerFn | TriggerOptions;
+export type Trigger = TriggerFn | TriggerOptions;
 
 /**
  * The options for a pipeline, including the name and the conditions under which the pipeline should run.
@@ -308,6 +487,10 @@ export interface PipelineOptions {
    * The trigger declares the conditions under which the pipeline should run.
    */
   on: Trigger;
+  /**
+   * The most jobs to run at the same time, by default every job that is ready runs. The `--jobs` flag of `cicada run` overrides this.
+   */
+  maxParallel?: number;
 }
 
 /**